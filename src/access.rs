@@ -0,0 +1,158 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of addresses in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A
+/// bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid IP range {0:?}")]
+pub struct IpRangeError(String);
+
+impl IpRange {
+    /// IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are matched as the IPv4
+    /// address they carry, so a dual-stack listener sees the same rules.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = IpRangeError;
+
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let invalid = || IpRangeError(range.to_string());
+
+        let (address, prefix_len) = match range.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (range, None),
+        };
+        let network = canonical(address.parse().map_err(|_| invalid())?);
+
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+
+        Ok(IpRange {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+/// Which peers may connect. Denied ranges always win; if any ranges are
+/// allowed, everything outside them is denied too.
+#[derive(Default)]
+pub(crate) struct AccessList {
+    pub(crate) allowed: Vec<IpRange>,
+    pub(crate) denied: Vec<IpRange>,
+}
+
+impl AccessList {
+    pub(crate) fn permits(&self, ip: IpAddr) -> bool {
+        if self.denied.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+
+        self.allowed.is_empty() || self.allowed.iter().any(|range| range.contains(ip))
+    }
+}
+
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn range(range: &str) -> IpRange {
+        range.parse().unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_blocks_and_bare_addresses() {
+        assert_eq!(range("10.1.2.3"), range("10.1.2.3/32"));
+        assert!(range("10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!range("10.1.2.3").contains(ip("10.1.2.4")));
+
+        for invalid in [
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0/8",
+            "10.0.0.0/x",
+            "host",
+        ] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn contains_addresses_under_the_prefix() {
+        assert!(range("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!range("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(range("0.0.0.0/0").contains(ip("192.0.2.1")));
+        assert!(range("fd00::/8").contains(ip("fd12::1")));
+        assert!(!range("fd00::/8").contains(ip("fe80::1")));
+        assert!(!range("0.0.0.0/0").contains(ip("::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_as_ipv4() {
+        assert!(range("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert!(range("::ffff:10.0.0.1").contains(ip("10.0.0.1")));
+        assert!(!range("::/0").contains(ip("::ffff:10.0.0.1")));
+    }
+
+    #[test]
+    fn denied_ranges_win_over_allowed_ones() {
+        let open = AccessList::default();
+        assert!(open.permits(ip("192.0.2.1")));
+
+        let list = AccessList {
+            allowed: vec![range("10.0.0.0/8")],
+            denied: vec![range("10.0.0.66")],
+        };
+        assert!(list.permits(ip("10.0.0.1")));
+        assert!(!list.permits(ip("10.0.0.66")));
+        assert!(!list.permits(ip("192.0.2.1")));
+
+        let deny_only = AccessList {
+            allowed: Vec::new(),
+            denied: vec![range("192.0.2.0/24")],
+        };
+        assert!(!deny_only.permits(ip("192.0.2.9")));
+        assert!(deny_only.permits(ip("198.51.100.1")));
+    }
+}
@@ -0,0 +1,228 @@
+use crate::base64;
+use crate::{HttpError, Request, Response};
+
+pub enum AuthResult {
+    Allowed,
+    /// Missing or invalid credentials. Answered with 401 and `challenge` as
+    /// the `WWW-Authenticate` header, e.g. `Basic realm="files"`.
+    Unauthorized {
+        challenge: String,
+    },
+    /// Valid credentials that are not allowed to make this request (403).
+    Forbidden,
+}
+
+impl AuthResult {
+    pub(crate) fn into_response(self) -> Option<Response> {
+        match self {
+            AuthResult::Allowed => None,
+            AuthResult::Unauthorized { challenge } => {
+                let mut response: Response = HttpError::Unauthorized.into();
+                response.set_header("WWW-Authenticate", &challenge);
+                Some(response)
+            }
+            AuthResult::Forbidden => Some(HttpError::Forbidden.into()),
+        }
+    }
+}
+
+/// Decides whether a request may reach its handler. Register one on the
+/// [`Server`](crate::Server) to cover every route, or on a single
+/// [`Route`](crate::Route) to override the server's for that route.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, req: &Request) -> AuthResult;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&Request) -> AuthResult + Send + Sync,
+{
+    fn authenticate(&self, req: &Request) -> AuthResult {
+        self(req)
+    }
+}
+
+/// HTTP Basic authentication against a single username and password.
+pub struct BasicAuth {
+    realm: String,
+    username: String,
+    password: String,
+}
+
+impl BasicAuth {
+    pub fn new(realm: &str, username: &str, password: &str) -> Self {
+        Self {
+            realm: realm.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+}
+
+impl Authenticator for BasicAuth {
+    fn authenticate(&self, req: &Request) -> AuthResult {
+        let credentials = credentials(req, "Basic")
+            .and_then(base64::decode)
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+
+        let authorized = match credentials.as_deref().and_then(|c| c.split_once(':')) {
+            Some((username, password)) => {
+                constant_time_eq(username.as_bytes(), self.username.as_bytes())
+                    & constant_time_eq(password.as_bytes(), self.password.as_bytes())
+            }
+            None => false,
+        };
+
+        if authorized {
+            AuthResult::Allowed
+        } else {
+            AuthResult::Unauthorized {
+                challenge: format!("Basic realm=\"{}\"", self.realm),
+            }
+        }
+    }
+}
+
+/// Bearer token authentication (RFC 6750) against a fixed set of tokens.
+pub struct BearerAuth {
+    realm: String,
+    tokens: Vec<String>,
+}
+
+impl BearerAuth {
+    pub fn new(realm: &str, tokens: &[&str]) -> Self {
+        Self {
+            realm: realm.to_string(),
+            tokens: tokens.iter().map(|token| token.to_string()).collect(),
+        }
+    }
+}
+
+impl Authenticator for BearerAuth {
+    fn authenticate(&self, req: &Request) -> AuthResult {
+        match credentials(req, "Bearer") {
+            Some(token)
+                if self
+                    .tokens
+                    .iter()
+                    .any(|known| constant_time_eq(known.as_bytes(), token.as_bytes())) =>
+            {
+                AuthResult::Allowed
+            }
+            Some(_) => AuthResult::Unauthorized {
+                challenge: format!("Bearer realm=\"{}\", error=\"invalid_token\"", self.realm),
+            },
+            None => AuthResult::Unauthorized {
+                challenge: format!("Bearer realm=\"{}\"", self.realm),
+            },
+        }
+    }
+}
+
+// The credentials following `scheme` in the Authorization header. Scheme names
+// are case-insensitive.
+fn credentials<'a>(req: &'a Request, scheme: &str) -> Option<&'a str> {
+    let (given_scheme, credentials) = req.get_header("Authorization")?.trim().split_once(' ')?;
+
+    if given_scheme.eq_ignore_ascii_case(scheme) {
+        Some(credentials.trim())
+    } else {
+        None
+    }
+}
+
+// Compares secrets without bailing out at the first differing byte, so response
+// timing does not reveal how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn request(authorization: Option<&str>) -> Request {
+        let header = authorization
+            .map(|value| format!("Authorization: {}\r\n", value))
+            .unwrap_or_default();
+        Request::new(&format!("GET / HTTP/1.1\r\n{}\r\n", header)).unwrap()
+    }
+
+    fn challenge(result: AuthResult) -> Option<String> {
+        match result {
+            AuthResult::Unauthorized { challenge } => Some(challenge),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn bearer_accepts_known_tokens() {
+        let auth = BearerAuth::new("api", &["one", "two"]);
+
+        assert!(matches!(
+            auth.authenticate(&request(Some("Bearer two"))),
+            AuthResult::Allowed
+        ));
+        assert!(matches!(
+            auth.authenticate(&request(Some("bearer  one "))),
+            AuthResult::Allowed
+        ));
+    }
+
+    #[test]
+    fn bearer_challenges_missing_and_unknown_tokens() {
+        let auth = BearerAuth::new("api", &["one"]);
+
+        assert_eq!(
+            challenge(auth.authenticate(&request(None))).as_deref(),
+            Some("Bearer realm=\"api\"")
+        );
+        assert_eq!(
+            challenge(auth.authenticate(&request(Some("Basic b25lOg==")))).as_deref(),
+            Some("Bearer realm=\"api\"")
+        );
+        assert_eq!(
+            challenge(auth.authenticate(&request(Some("Bearer on")))).as_deref(),
+            Some("Bearer realm=\"api\", error=\"invalid_token\"")
+        );
+    }
+
+    #[test]
+    fn basic_checks_username_and_password() {
+        let auth = BasicAuth::new("files", "user", "pass");
+
+        // "user:pass" and "user:nope".
+        assert!(matches!(
+            auth.authenticate(&request(Some("Basic dXNlcjpwYXNz"))),
+            AuthResult::Allowed
+        ));
+        assert_eq!(
+            challenge(auth.authenticate(&request(Some("Basic dXNlcjpub3Bl")))).as_deref(),
+            Some("Basic realm=\"files\"")
+        );
+    }
+
+    #[test]
+    fn unauthorized_becomes_a_401_with_the_challenge() {
+        let result = AuthResult::Unauthorized {
+            challenge: "Bearer realm=\"api\"".to_string(),
+        };
+
+        let response = result.into_response().unwrap();
+
+        assert_eq!(response.status_code(), 401);
+        assert_eq!(
+            response.get_header("WWW-Authenticate"),
+            Some("Bearer realm=\"api\"")
+        );
+        assert_eq!(
+            AuthResult::Forbidden.into_response().unwrap().status_code(),
+            403
+        );
+    }
+}
@@ -0,0 +1,54 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() / 3 * 4 + 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        encoded.push(ALPHABET[(n >> 18) as usize & 63] as char);
+        encoded.push(ALPHABET[(n >> 12) as usize & 63] as char);
+        encoded.push(if chunk.len() > 1 {
+            ALPHABET[(n >> 6) as usize & 63] as char
+        } else {
+            '='
+        });
+        encoded.push(if chunk.len() > 2 {
+            ALPHABET[n as usize & 63] as char
+        } else {
+            '='
+        });
+    }
+    encoded
+}
+
+/// Decodes standard, padded base64. Returns `None` for anything malformed.
+pub(crate) fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let chunks = encoded.as_bytes().chunks_exact(4);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+
+    let count = chunks.len();
+    let mut decoded = Vec::with_capacity(count * 3);
+    for (i, chunk) in chunks.enumerate() {
+        let last = i == count - 1;
+        let padding = chunk.iter().rev().take_while(|&&byte| byte == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut n = 0u32;
+        for &byte in &chunk[..4 - padding] {
+            let value = ALPHABET.iter().position(|&c| c == byte)? as u32;
+            n = n << 6 | value;
+        }
+        n <<= 6 * padding as u32;
+
+        let bytes = n.to_be_bytes();
+        decoded.extend_from_slice(&bytes[1..4 - padding]);
+    }
+
+    Some(decoded)
+}
@@ -0,0 +1,83 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::fs::{File, OpenOptions};
+
+// Numbers temp files within this process; the process id keeps servers
+// sharing a temp directory apart.
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// A request body kept in a temp file rather than in memory. The file is
+/// removed when this is dropped, unless it has been moved into place with
+/// [`persist`](Self::persist) first.
+#[derive(Debug)]
+pub(crate) struct SpilledBody {
+    path: PathBuf,
+    len: usize,
+}
+
+impl SpilledBody {
+    // A new, empty temp file for a body of `len` bytes, and the file to write
+    // them to.
+    pub(crate) async fn create(len: usize) -> std::io::Result<(SpilledBody, File)> {
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "http-server-body-{}-{}",
+                std::process::id(),
+                NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+            ));
+
+            // A file left behind by an earlier process with the same id is
+            // never reused; it is skipped for the next name.
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+            {
+                Ok(file) => return Ok((SpilledBody { path, len }, file)),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // For a body whose length was only known once it had all been written.
+    pub(crate) fn with_len(mut self, len: usize) -> Self {
+        self.len = len;
+        self
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    // Moves the file to `to`. Renaming fails across filesystems, in which case
+    // it is copied instead and the temp file left for `drop` to remove. A copy
+    // that fails part way is removed again.
+    pub(crate) async fn persist(&self, to: &Path) -> std::io::Result<()> {
+        if tokio::fs::rename(&self.path, to).await.is_ok() {
+            return Ok(());
+        }
+
+        match tokio::fs::copy(&self.path, to).await {
+            Ok(_) => Ok(()),
+            // Without the temp file (it was moved already) nothing was written.
+            Err(err) if !tokio::fs::try_exists(&self.path).await.unwrap_or(false) => Err(err),
+            Err(err) => {
+                let _ = tokio::fs::remove_file(to).await;
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Drop for SpilledBody {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,746 @@
+use std::fmt::Display;
+
+use crate::ContentType;
+
+// The types compressed unless the server is configured otherwise: text, and
+// the structured formats that are text underneath.
+const DEFAULT_COMPRESSIBLE_TYPES: [&str; 7] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "image/svg+xml",
+    "*/*+json",
+    "*/*+xml",
+];
+
+/// Which response content types are compressed for clients that accept it.
+/// Patterns are `type/subtype`, `type/*`, `*/*+suffix` (for example
+/// `*/*+json`) or `*/*`, matched case-insensitively against the media type
+/// with any parameters left off. A response without a `Content-Type` is never
+/// compressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompressibleTypes {
+    /// Only types matching one of the patterns.
+    Only(Vec<String>),
+    /// Every type except those matching one of the patterns.
+    AllBut(Vec<String>),
+}
+
+impl CompressibleTypes {
+    pub fn allows(&self, content_type: &str) -> bool {
+        let Some(media_type) = ContentType::parse(content_type).map(|parsed| parsed.media_type)
+        else {
+            return false;
+        };
+
+        match self {
+            CompressibleTypes::Only(patterns) => patterns
+                .iter()
+                .any(|pattern| matches_pattern(pattern, &media_type)),
+            CompressibleTypes::AllBut(patterns) => !patterns
+                .iter()
+                .any(|pattern| matches_pattern(pattern, &media_type)),
+        }
+    }
+}
+
+impl Default for CompressibleTypes {
+    fn default() -> Self {
+        CompressibleTypes::Only(
+            DEFAULT_COMPRESSIBLE_TYPES
+                .iter()
+                .map(|pattern| pattern.to_string())
+                .collect(),
+        )
+    }
+}
+
+fn matches_pattern(pattern: &str, media_type: &str) -> bool {
+    let pattern = pattern.trim().to_ascii_lowercase();
+
+    if pattern == "*/*" {
+        true
+    } else if let Some(suffix) = pattern.strip_prefix("*/*") {
+        suffix.starts_with('+') && media_type.ends_with(suffix)
+    } else if let Some(main_type) = pattern.strip_suffix('*') {
+        main_type.ends_with('/') && media_type.starts_with(main_type)
+    } else {
+        pattern == media_type
+    }
+}
+
+/// A content coding the server can compress response bodies with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    // In order of preference when the client weights several the same.
+    const SUPPORTED: [Encoding; 2] = [Encoding::Gzip, Encoding::Deflate];
+
+    /// The encoding to use for a request's `Accept-Encoding`, if it accepts
+    /// one we support: the one with the highest `q`, gzip on a tie. Names are
+    /// matched case-insensitively, with `x-gzip` taken for `gzip`, `*` stands
+    /// for any coding not listed by name, and `q=0` rules a coding out.
+    /// Entries with a malformed `q` are ignored.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+        let weights: Vec<(&str, u16)> = accept_encoding?
+            .split(',')
+            .filter_map(|element| {
+                let mut parts = element.split(';');
+                let coding = parts.next().unwrap_or("").trim();
+                let mut weight = 1000;
+                for param in parts {
+                    if let Some((name, value)) = param.split_once('=') {
+                        if name.trim().eq_ignore_ascii_case("q") {
+                            weight = parse_qvalue(value.trim())?;
+                        }
+                    }
+                }
+                (!coding.is_empty()).then_some((coding, weight))
+            })
+            .collect();
+
+        let weight_of = |encoding: Encoding| {
+            let named = weights
+                .iter()
+                .filter(|(coding, _)| encoding.is_named(coding))
+                .map(|&(_, weight)| weight)
+                .max();
+            named.or_else(|| {
+                weights
+                    .iter()
+                    .filter(|(coding, _)| *coding == "*")
+                    .map(|&(_, weight)| weight)
+                    .max()
+            })
+        };
+
+        Self::SUPPORTED
+            .iter()
+            .filter_map(|&encoding| Some((encoding, weight_of(encoding)?)))
+            .filter(|&(_, weight)| weight > 0)
+            // max_by_key keeps the last of equal keys, so look from the end.
+            .rev()
+            .max_by_key(|&(_, weight)| weight)
+            .map(|(encoding, _)| encoding)
+    }
+
+    // `x-gzip` is the old name RFC 9110 says to treat as gzip.
+    fn is_named(self, coding: &str) -> bool {
+        match self {
+            Encoding::Gzip => {
+                coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip")
+            }
+            Encoding::Deflate => coding.eq_ignore_ascii_case("deflate"),
+        }
+    }
+
+    pub(crate) fn encode(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Gzip => gzip(data),
+            Encoding::Deflate => zlib(data),
+        }
+    }
+}
+
+// A qvalue (RFC 9110 section 12.4.2) in thousandths: `0` to `1` with at most
+// three decimal places.
+fn parse_qvalue(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let thousandths = format!("{:0<3}", fraction).parse::<u16>().ok()?;
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::Gzip => write!(f, "gzip"),
+            Encoding::Deflate => write!(f, "deflate"),
+        }
+    }
+}
+
+// RFC 1952: a fixed ten byte header (no name, no mtime, unknown OS), the
+// deflate stream, then the CRC-32 and length of the input.
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+// The `deflate` content coding is really RFC 1950 zlib: a two byte header
+// (32 KiB window, no dictionary), the deflate stream, then the Adler-32 of
+// the input.
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_HASH_BITS: u32 = 8;
+const MAX_HASH_BITS: u32 = 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// A raw DEFLATE stream (RFC 1951). The input is matched against the previous
+// 32 KiB with hash chains and coded as a single block using the fixed Huffman
+// codes, which needs no code tables in the output and compresses text well
+// enough. Input that does not shrink that way is sent as stored blocks.
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    bits.write(1, 1);
+    bits.write(1, 2);
+
+    for token in lz77(data) {
+        match token {
+            Token::Literal(byte) => write_fixed_literal(&mut bits, byte as u16),
+            Token::Match { length, distance } => {
+                let index = LENGTH_BASE.partition_point(|&base| base <= length) - 1;
+                write_fixed_literal(&mut bits, 257 + index as u16);
+                bits.write(
+                    (length - LENGTH_BASE[index]) as u32,
+                    LENGTH_EXTRA[index] as u32,
+                );
+
+                let index = DISTANCE_BASE.partition_point(|&base| base <= distance) - 1;
+                bits.write(reverse_bits(index as u32, 5), 5);
+                bits.write(
+                    (distance - DISTANCE_BASE[index]) as u32,
+                    DISTANCE_EXTRA[index] as u32,
+                );
+            }
+        }
+    }
+    write_fixed_literal(&mut bits, 256);
+
+    let compressed = bits.finish();
+    let stored_len = data.len() + 5 * (data.len() / 0xffff + 1);
+    if compressed.len() <= stored_len {
+        compressed
+    } else {
+        stored(data)
+    }
+}
+
+fn stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 5 * (data.len() / 0xffff + 1));
+    let mut blocks = data.chunks(0xffff).peekable();
+
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        out.push(u8::from(blocks.peek().is_none()));
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out
+}
+
+enum Token {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+fn lz77(data: &[u8]) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chains = HashChains::new(data);
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let (length, distance) = chains.longest_match(pos);
+
+        if length >= MIN_MATCH {
+            for offset in 0..length {
+                chains.insert(pos + offset);
+            }
+            tokens.push(Token::Match {
+                length: length as u16,
+                distance: distance as u16,
+            });
+            pos += length;
+        } else {
+            chains.insert(pos);
+            tokens.push(Token::Literal(data[pos]));
+            pos += 1;
+        }
+    }
+
+    tokens
+}
+
+// For every three byte sequence seen, the most recent position it started at
+// (`head`), and for each position in the window the previous position with
+// the same hash (`prev`). Both are sized for the input, up to the full window,
+// so small bodies don't pay for tables they can't fill.
+struct HashChains<'a> {
+    data: &'a [u8],
+    hash_bits: u32,
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl<'a> HashChains<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        let window = data.len().clamp(1, WINDOW_SIZE);
+        let hash_bits = window
+            .next_power_of_two()
+            .trailing_zeros()
+            .clamp(MIN_HASH_BITS, MAX_HASH_BITS);
+
+        Self {
+            data,
+            hash_bits,
+            head: vec![usize::MAX; 1 << hash_bits],
+            prev: vec![usize::MAX; window],
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let data = self.data;
+        let value = u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], 0]);
+        (value.wrapping_mul(0x9e3779b1) >> (32 - self.hash_bits)) as usize
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH <= self.data.len() {
+            let bucket = self.hash(pos);
+            let slot = pos % self.prev.len();
+            self.prev[slot] = self.head[bucket];
+            self.head[bucket] = pos;
+        }
+    }
+
+    // The longest earlier match for the bytes at `pos`, as (length, distance).
+    fn longest_match(&self, pos: usize) -> (usize, usize) {
+        let data = self.data;
+        if pos + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+
+        let max_length = MAX_MATCH.min(data.len() - pos);
+        let mut best = (0, 0);
+        let mut candidate = self.head[self.hash(pos)];
+
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || pos - candidate > WINDOW_SIZE {
+                break;
+            }
+
+            let length = data[candidate..]
+                .iter()
+                .zip(&data[pos..pos + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.0 {
+                best = (length, pos - candidate);
+                if length == max_length {
+                    break;
+                }
+            }
+
+            let next = self.prev[candidate % self.prev.len()];
+            // Slots are reused as the window moves; an entry pointing forwards
+            // belongs to a newer position and ends the chain.
+            if next == usize::MAX || next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+
+        best
+    }
+}
+
+// The fixed literal/length code of RFC 1951 section 3.2.6.
+fn write_fixed_literal(bits: &mut BitWriter, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol as u32, 8),
+        144..=255 => (0x190 + (symbol - 144) as u32, 9),
+        256..=279 => ((symbol - 256) as u32, 7),
+        _ => (0xc0 + (symbol - 280) as u32, 8),
+    };
+    bits.write(reverse_bits(code, len), len);
+}
+
+// Huffman codes are packed starting from their most significant bit, while
+// everything else in DEFLATE is packed from the least significant one.
+fn reverse_bits(code: u32, len: u32) -> u32 {
+    code.reverse_bits() >> (32 - len)
+}
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, len: u32) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += len;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    // The most bytes that can be summed before `b` could overflow.
+    const BLOCK: usize = 5552;
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for block in data.chunks(BLOCK) {
+        for &byte in block {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn content_types_match_exactly_by_main_type_or_by_suffix() {
+        let types = CompressibleTypes::default();
+
+        for (content_type, expected) in [
+            ("text/html", true),
+            ("Text/Plain; charset=utf-8", true),
+            ("application/json", true),
+            ("application/problem+json", true),
+            ("image/svg+xml", true),
+            ("application/octet-stream", false),
+            ("image/png", false),
+            ("application/jsonx", false),
+            ("", false),
+        ] {
+            assert_eq!(types.allows(content_type), expected, "{}", content_type);
+        }
+    }
+
+    #[test]
+    fn a_denylist_allows_everything_it_does_not_name() {
+        let types = CompressibleTypes::AllBut(vec!["image/*".to_string(), "video/*".to_string()]);
+
+        assert!(types.allows("application/octet-stream"));
+        assert!(types.allows("text/css"));
+        assert!(!types.allows("image/png"));
+        assert!(!types.allows("video/mp4"));
+        assert!(!types.allows(""));
+    }
+
+    // Just enough of an inflater to read back what `deflate` writes: stored
+    // and fixed Huffman blocks. Returns the data and how many bytes the
+    // stream took up.
+    fn inflate(data: &[u8]) -> (Vec<u8>, usize) {
+        let mut bits = BitReader { data, pos: 0 };
+        let mut out = Vec::new();
+
+        loop {
+            let last = bits.read(1) == 1;
+            match bits.read(2) {
+                0 => {
+                    bits.align();
+                    let len = bits.read(16) as usize;
+                    assert_eq!(bits.read(16) as usize, !len & 0xffff, "bad NLEN");
+                    let start = bits.pos / 8;
+                    out.extend_from_slice(&data[start..start + len]);
+                    bits.pos += len * 8;
+                }
+                1 => loop {
+                    let symbol = bits.fixed_literal();
+                    if symbol < 256 {
+                        out.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        break;
+                    }
+
+                    let index = (symbol - 257) as usize;
+                    let length = LENGTH_BASE[index] as usize
+                        + bits.read(LENGTH_EXTRA[index] as u32) as usize;
+                    let index = bits.read_code(5) as usize;
+                    let distance = DISTANCE_BASE[index] as usize
+                        + bits.read(DISTANCE_EXTRA[index] as u32) as usize;
+
+                    let start = out.len() - distance;
+                    for i in 0..length {
+                        out.push(out[start + i]);
+                    }
+                },
+                block_type => panic!("unexpected block type {}", block_type),
+            }
+
+            if last {
+                bits.align();
+                return (out, bits.pos / 8);
+            }
+        }
+    }
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u32 {
+            let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+            self.pos += 1;
+            bit as u32
+        }
+
+        // Skips to the next byte boundary.
+        fn align(&mut self) {
+            self.pos += (8 - self.pos % 8) % 8;
+        }
+
+        // A value packed from its least significant bit.
+        fn read(&mut self, len: u32) -> u32 {
+            (0..len).fold(0, |value, i| value | self.bit() << i)
+        }
+
+        // A Huffman code, packed from its most significant bit.
+        fn read_code(&mut self, len: u32) -> u32 {
+            (0..len).fold(0, |code, _| code << 1 | self.bit())
+        }
+
+        fn fixed_literal(&mut self) -> u16 {
+            let code = self.read_code(7);
+            if code <= 0x17 {
+                return 256 + code as u16;
+            }
+            let code = code << 1 | self.bit();
+            match code {
+                0x30..=0xbf => return (code - 0x30) as u16,
+                0xc0..=0xc7 => return (280 + code - 0xc0) as u16,
+                _ => {}
+            }
+            let code = code << 1 | self.bit();
+            (144 + code - 0x190) as u16
+        }
+    }
+
+    fn samples() -> Vec<Vec<u8>> {
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(200);
+        // xorshift, so the bytes don't compress and go out stored, in more
+        // than one block.
+        let mut state = 0x2545f491u32;
+        let noise = (0..70_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+
+        vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcabcabcabc".to_vec(),
+            text.into_bytes(),
+            vec![0; 100_000],
+            (0..=255).cycle().take(40_000).collect(),
+            noise,
+        ]
+    }
+
+    #[test]
+    fn checksums_match_known_values() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        // Long enough for the sums to need reducing along the way.
+        assert_eq!(adler32(&vec![0xff; 100_000]), 0x149a302c);
+        assert_eq!(crc32(&vec![0xff; 100_000]), 0x68c6cec4);
+    }
+
+    #[test]
+    fn gzip_round_trips() {
+        for data in samples() {
+            let encoded = gzip(&data);
+
+            assert_eq!(encoded[..4], [0x1f, 0x8b, 8, 0]);
+            let (decoded, used) = inflate(&encoded[10..]);
+            assert!(
+                decoded == data,
+                "gzip changed {} bytes of input",
+                data.len()
+            );
+
+            let trailer = &encoded[10 + used..];
+            assert_eq!(trailer.len(), 8);
+            assert_eq!(trailer[..4], crc32(&data).to_le_bytes());
+            assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn deflate_is_zlib_and_round_trips() {
+        for data in samples() {
+            let encoded = zlib(&data);
+
+            assert_eq!(u16::from_be_bytes([encoded[0], encoded[1]]) % 31, 0);
+            let (decoded, used) = inflate(&encoded[2..]);
+            assert!(
+                decoded == data,
+                "deflate changed {} bytes of input",
+                data.len()
+            );
+            assert_eq!(encoded[2 + used..], adler32(&data).to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn repetitive_text_shrinks() {
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(200);
+
+        assert!(gzip(text.as_bytes()).len() < text.len() / 10);
+    }
+
+    #[test]
+    fn hash_tables_are_sized_for_the_input() {
+        let small = HashChains::new(b"hello");
+        assert_eq!((small.head.len(), small.prev.len()), (256, 5));
+
+        let large = vec![0; 100_000];
+        let large = HashChains::new(&large);
+        assert_eq!(
+            (large.head.len(), large.prev.len()),
+            (1 << MAX_HASH_BITS, WINDOW_SIZE)
+        );
+    }
+
+    #[test]
+    fn negotiates_a_single_coding() {
+        assert_eq!(Encoding::negotiate(Some("gzip")), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate(Some(" GZip ")), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate(Some("x-gzip")), Some(Encoding::Gzip));
+        assert_eq!(
+            Encoding::negotiate(Some("deflate")),
+            Some(Encoding::Deflate)
+        );
+    }
+
+    #[test]
+    fn picks_the_highest_weight_from_a_list() {
+        assert_eq!(
+            Encoding::negotiate(Some("br, deflate;q=0.9, gzip;q=0.5")),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("deflate, gzip")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("gzip;q=0, *;q=0.1")),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("gzip;q=2, deflate;q=0.001")),
+            Some(Encoding::Deflate)
+        );
+    }
+
+    #[test]
+    fn nothing_when_no_supported_coding_is_acceptable() {
+        assert_eq!(Encoding::negotiate(None), None);
+        assert_eq!(Encoding::negotiate(Some("")), None);
+        assert_eq!(Encoding::negotiate(Some("br, zstd")), None);
+        assert_eq!(Encoding::negotiate(Some("gzip;q=0, deflate;q=0")), None);
+        assert_eq!(Encoding::negotiate(Some("identity, *;q=0")), None);
+    }
+
+    #[test]
+    fn qvalues_have_at_most_three_decimals() {
+        assert_eq!(parse_qvalue("1"), Some(1000));
+        assert_eq!(parse_qvalue("1.000"), Some(1000));
+        assert_eq!(parse_qvalue("0.25"), Some(250));
+        assert_eq!(parse_qvalue("0"), Some(0));
+        assert_eq!(parse_qvalue("0.2500"), None);
+        assert_eq!(parse_qvalue("1.5"), None);
+        assert_eq!(parse_qvalue("-0"), None);
+    }
+}
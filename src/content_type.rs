@@ -0,0 +1,149 @@
+use crate::request::is_token;
+
+/// A parsed `Content-Type` value (RFC 9110 section 8.3): the media type and
+/// its parameters. The type and parameter names are lowercased since they are
+/// case-insensitive; parameter values are kept as sent, with the quotes and
+/// escapes of quoted strings removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    pub media_type: String,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl ContentType {
+    /// Returns `None` if the media type is not a `type/subtype` pair.
+    /// Malformed parameters are skipped rather than failing the whole value.
+    pub fn parse(value: &str) -> Option<ContentType> {
+        let (media_type, mut rest) = value.split_once(';').unwrap_or((value, ""));
+
+        let media_type = media_type.trim();
+        match media_type.split_once('/') {
+            Some((kind, subtype)) if is_token(kind) && is_token(subtype) => {}
+            _ => return None,
+        }
+
+        let mut parameters = Vec::new();
+        while !rest.is_empty() {
+            let (parameter, remaining) = split_parameter(rest);
+            rest = remaining;
+
+            if let Some((name, value)) = parameter.split_once('=') {
+                let name = name.trim();
+                let value = unquote(value.trim());
+                if is_token(name) {
+                    parameters.push((name.to_ascii_lowercase(), value));
+                }
+            }
+        }
+
+        Some(ContentType {
+            media_type: media_type.to_ascii_lowercase(),
+            parameters,
+        })
+    }
+
+    /// The first parameter with this name, compared case-insensitively.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+
+    pub fn boundary(&self) -> Option<&str> {
+        self.param("boundary")
+    }
+
+    /// Whether the media type is `kind/*`, e.g. `is("text")`.
+    pub fn is(&self, kind: &str) -> bool {
+        self.media_type
+            .split_once('/')
+            .is_some_and(|(given, _)| given.eq_ignore_ascii_case(kind))
+    }
+}
+
+// Splits off the next `;`-separated parameter, skipping semicolons inside
+// quoted strings.
+fn split_parameter(input: &str) -> (&str, &str) {
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (i, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => return (&input[..i], &input[i + 1..]),
+            _ => {}
+        }
+    }
+
+    (input, "")
+}
+
+fn unquote(value: &str) -> String {
+    let inner = match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(inner) => inner,
+        None => return value.to_string(),
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_unquoted_parameters() {
+        let content_type = ContentType::parse("Text/HTML; Charset=UTF-8").unwrap();
+
+        assert_eq!(content_type.media_type, "text/html");
+        assert_eq!(content_type.charset(), Some("UTF-8"));
+        assert!(content_type.is("text"));
+    }
+
+    #[test]
+    fn quoted_values_may_hold_semicolons_and_escapes() {
+        let content_type =
+            ContentType::parse(r#"multipart/form-data; boundary="a;b \"c\""; charset=utf-8"#)
+                .unwrap();
+
+        assert_eq!(content_type.boundary(), Some(r#"a;b "c""#));
+        assert_eq!(content_type.charset(), Some("utf-8"));
+    }
+
+    #[test]
+    fn malformed_parameters_are_skipped() {
+        let content_type =
+            ContentType::parse("text/plain; nonsense; b@d=1; charset=ascii").unwrap();
+
+        assert_eq!(
+            content_type.parameters,
+            vec![("charset".to_string(), "ascii".to_string())]
+        );
+    }
+
+    #[test]
+    fn media_type_must_be_a_type_subtype_pair() {
+        assert_eq!(ContentType::parse("text"), None);
+        assert_eq!(ContentType::parse("text/"), None);
+        assert_eq!(ContentType::parse("te xt/plain"), None);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{Response, StatusCode};
+
+/// Errors a handler can return instead of building an error response itself.
+/// Each variant maps to one status code:
+///
+/// | Variant              | Status                    |
+/// |----------------------|---------------------------|
+/// | `BadRequest`         | 400 Bad Request           |
+/// | `Unauthorized`       | 401 Unauthorized          |
+/// | `Forbidden`          | 403 Forbidden             |
+/// | `NotFound`           | 404 Not Found             |
+/// | `MethodNotAllowed`   | 405 Method Not Allowed    |
+/// | `PayloadTooLarge`    | 413 Content Too Large     |
+/// | `Internal`           | 500 Internal Server Error |
+/// | `NotImplemented`     | 501 Not Implemented       |
+/// | `ServiceUnavailable` | 503 Service Unavailable   |
+///
+/// The message of a `BadRequest` is sent to the client as the body. The cause
+/// of an `Internal` error is logged but only sent in debug mode, since it may
+/// describe server internals. `ServiceUnavailable` also sets `Retry-After`.
+#[derive(Debug, thiserror::Error)]
+pub enum HttpError {
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("forbidden")]
+    Forbidden,
+    #[error("not found")]
+    NotFound,
+    #[error("method not allowed")]
+    MethodNotAllowed,
+    #[error("payload too large")]
+    PayloadTooLarge,
+    #[error("internal error: {0:#}")]
+    Internal(#[from] anyhow::Error),
+    #[error("not implemented")]
+    NotImplemented,
+    #[error("service unavailable, retry after {retry_after}s")]
+    ServiceUnavailable { retry_after: u32 },
+}
+
+impl HttpError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HttpError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            HttpError::Unauthorized => StatusCode::UNAUTHORIZED,
+            HttpError::Forbidden => StatusCode::FORBIDDEN,
+            HttpError::NotFound => StatusCode::NOT_FOUND,
+            HttpError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            HttpError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            HttpError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            HttpError::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+            HttpError::ServiceUnavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl From<HttpError> for Response {
+    fn from(err: HttpError) -> Self {
+        let status = err.status();
+        let status_text = status.reason_phrase();
+
+        let body = match &err {
+            HttpError::BadRequest(message) => message.as_str(),
+            _ => status_text,
+        };
+
+        let mut response = Response::with_status(status);
+        if let HttpError::Internal(cause) = &err {
+            eprintln!("Internal error: {:#}", cause);
+            response.set_detail(format!("{:#}", cause));
+        }
+        if let HttpError::ServiceUnavailable { retry_after } = err {
+            response.set_header("Retry-After", &retry_after.to_string());
+        }
+        response.set_body(body);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn response(err: HttpError) -> (u32, String) {
+        let response = Response::from(err);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        (response.status_code(), body)
+    }
+
+    #[test]
+    fn each_variant_maps_to_its_status() {
+        let cases = [
+            (HttpError::BadRequest("no".to_string()), 400, "no"),
+            (HttpError::Unauthorized, 401, "Unauthorized"),
+            (HttpError::Forbidden, 403, "Forbidden"),
+            (HttpError::NotFound, 404, "Not Found"),
+            (HttpError::MethodNotAllowed, 405, "Method Not Allowed"),
+            (HttpError::PayloadTooLarge, 413, "Content Too Large"),
+            (
+                HttpError::Internal(anyhow::anyhow!("disk on fire")),
+                500,
+                "Internal Server Error",
+            ),
+            (HttpError::NotImplemented, 501, "Not Implemented"),
+            (
+                HttpError::ServiceUnavailable { retry_after: 1 },
+                503,
+                "Service Unavailable",
+            ),
+        ];
+
+        for (err, status, body) in cases {
+            assert_eq!(response(err), (status, body.to_string()));
+        }
+    }
+}
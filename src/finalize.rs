@@ -0,0 +1,484 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::request::has_token;
+use crate::{CompressibleTypes, ContentType, Encoding, Request, Response, StatusCode, Verb};
+
+/// Server-wide settings for [`finalize`].
+pub(crate) struct FinalizeConfig {
+    pub(crate) default_headers: Vec<(String, String)>,
+    pub(crate) server_header: Option<String>,
+    pub(crate) charset: Option<String>,
+    pub(crate) compression: bool,
+    pub(crate) compressible_types: CompressibleTypes,
+    // Bodies shorter than this are sent as they are; empty ones always are.
+    pub(crate) compress_min_size: usize,
+}
+
+impl Default for FinalizeConfig {
+    fn default() -> Self {
+        Self {
+            default_headers: Vec::new(),
+            server_header: None,
+            charset: None,
+            compression: true,
+            compressible_types: CompressibleTypes::default(),
+            compress_min_size: 0,
+        }
+    }
+}
+
+/// Applies every automatic change to a response, once, right before it is
+/// sent. Handlers, filters and built-in error responses all pass through here,
+/// so they all get the same treatment. In order:
+///
+/// 1. Default headers are added where the response has not set them itself.
+/// 2. `Server` is added, if configured and not already set.
+/// 3. `Date` is set to the current time, if not already set.
+/// 4. `text/*` content types without a charset get the configured one.
+/// 5. Statuses that cannot carry a body (1xx, 204, 304) lose it, and 1xx and
+///    204 also lose any `Content-Length`.
+/// 6. If compression is on, bodies of a compressible type and at least the
+///    minimum size are compressed for clients whose `Accept-Encoding` allows it
+///    (see [`Encoding::negotiate`]), and get
+///    `Vary: Accept-Encoding` either way. Responses that already have a
+///    `Content-Encoding`, partial responses and streams are left alone.
+/// 7. The body is framed: `Content-Length` is set from the final body,
+///    replacing whatever the handler put there, while streamed bodies keep
+///    the `Content-Length` the handler gave them or else are sent chunked to
+///    HTTP/1.1 clients and delimited by closing the connection for HTTP/1.0
+///    ones.
+/// 8. `Connection: close` is set if this is the last response on the
+///    connection: the request could not be read, asked to close, is HTTP/1.0
+///    without `keep-alive`, its body is delimited by the close, or the handler
+///    set `Connection: close` itself. HTTP/1.0 requests that are kept alive
+///    get `Connection: keep-alive`.
+/// 9. Responses to HEAD requests lose their body, keeping the framing headers
+///    the matching GET would have had.
+///
+/// Framing comes late so that every step that touches the body runs before its
+/// length is taken. `req` is the request being answered, if it could be
+/// parsed.
+pub(crate) fn finalize(response: &mut Response, req: Option<&Request>, config: &FinalizeConfig) {
+    for (key, value) in &config.default_headers {
+        if response.get_header(key).is_none() {
+            response.set_header(key, value);
+        }
+    }
+
+    if let Some(server) = &config.server_header {
+        if response.get_header("Server").is_none() {
+            response.set_header("Server", server);
+        }
+    }
+
+    if response.get_header("Date").is_none() {
+        response.set_header("Date", &http_date(SystemTime::now()));
+    }
+
+    if let Some(charset) = &config.charset {
+        if let Some(content_type) = response.get_header("Content-Type") {
+            let needs_charset = ContentType::parse(content_type)
+                .is_some_and(|parsed| parsed.is("text") && parsed.charset().is_none());
+            if needs_charset {
+                let content_type = format!("{}; charset={}", content_type, charset);
+                response.remove_header("Content-Type");
+                response.set_header("Content-Type", &content_type);
+            }
+        }
+    }
+
+    let http_1_0 = req.is_some_and(|req| req.version == "HTTP/1.0");
+    let mut keep_alive = req.is_some_and(Request::keep_alive)
+        && !has_token(response.get_header("Connection"), "close");
+
+    if !response.permits_body() {
+        response.set_body_bytes(&[]);
+        response.take_body_stream();
+        if response.status() != StatusCode::NOT_MODIFIED {
+            response.remove_header("Content-Length");
+        }
+    } else {
+        if config.compression && is_compressible(response, config) {
+            response.vary_on_accept_encoding();
+            if let Some(encoding) =
+                req.and_then(|req| Encoding::negotiate(req.get_header("Accept-Encoding")))
+            {
+                response.compress_with(encoding);
+            }
+        }
+
+        let stream_length = response
+            .get_header("Content-Length")
+            .and_then(|length| length.trim().parse::<u64>().ok())
+            .filter(|_| response.has_body_stream());
+
+        response.remove_header("Content-Length");
+        response.remove_header("Transfer-Encoding");
+        if !response.has_body_stream() {
+            response.set_header("Content-Length", &response.body().len().to_string());
+        } else if let Some(length) = stream_length {
+            response.set_header("Content-Length", &length.to_string());
+        } else if http_1_0 || req.is_none() {
+            keep_alive = false;
+        } else {
+            response.set_header("Transfer-Encoding", "chunked");
+        }
+    }
+
+    if !keep_alive {
+        response.remove_header("Connection");
+        response.set_header("Connection", "close");
+    } else if http_1_0 && response.get_header("Connection").is_none() {
+        response.set_header("Connection", "keep-alive");
+    }
+
+    if req.is_some_and(|req| req.verb == Verb::Head) {
+        response.set_body_bytes(&[]);
+        response.take_body_stream();
+    }
+}
+
+fn is_compressible(response: &Response, config: &FinalizeConfig) -> bool {
+    !response.has_body_stream()
+        && !response.body().is_empty()
+        && response.body().len() >= config.compress_min_size
+        && response.status() != StatusCode::PARTIAL_CONTENT
+        && response.get_header("Content-Range").is_none()
+        && response.get_header("Content-Encoding").is_none()
+        && response
+            .get_header("Content-Type")
+            .is_some_and(|content_type| config.compressible_types.allows(content_type))
+}
+
+// Formats a time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let days = seconds / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+// Howard Hinnant's days-to-civil algorithm: days since 1970-01-01 to a
+// (year, month, day) in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn get(version: &str) -> Request {
+        Request::new(&format!("GET / {}\r\nHost: x\r\n\r\n", version)).unwrap()
+    }
+
+    fn with_status(status_code: u32) -> Response {
+        let mut response = Response::new();
+        response.set_status_code(status_code);
+        response.set_header("Content-Length", "5");
+        response.set_body("hello");
+        response
+    }
+
+    #[test]
+    fn content_length_is_taken_from_the_final_body() {
+        let mut response = Response::new();
+        response.set_header("Content-Length", "99");
+        response.set_body("hello");
+
+        finalize(
+            &mut response,
+            Some(&get("HTTP/1.1")),
+            &FinalizeConfig::default(),
+        );
+
+        assert_eq!(response.get_header("Content-Length"), Some("5"));
+    }
+
+    #[test]
+    fn bodiless_statuses_lose_their_body_but_304_keeps_its_length() {
+        for status_code in [101, 204, 304] {
+            let mut response = with_status(status_code);
+
+            finalize(
+                &mut response,
+                Some(&get("HTTP/1.1")),
+                &FinalizeConfig::default(),
+            );
+
+            let length = (status_code == 304).then_some("5");
+            assert_eq!(response.body(), b"", "{}", status_code);
+            assert_eq!(
+                response.get_header("Content-Length"),
+                length,
+                "{}",
+                status_code
+            );
+        }
+    }
+
+    #[test]
+    fn streams_are_chunked_for_http_1_1_and_close_delimited_for_http_1_0() {
+        for (version, transfer_encoding, connection) in [
+            ("HTTP/1.1", Some("chunked"), None),
+            ("HTTP/1.0", None, Some("close")),
+        ] {
+            let mut response = Response::new();
+            response.set_body_stream(tokio::sync::mpsc::channel(1).1);
+
+            finalize(
+                &mut response,
+                Some(&get(version)),
+                &FinalizeConfig::default(),
+            );
+
+            assert_eq!(response.get_header("Transfer-Encoding"), transfer_encoding);
+            assert_eq!(response.get_header("Connection"), connection);
+            assert_eq!(response.get_header("Content-Length"), None);
+        }
+    }
+
+    #[test]
+    fn charset_is_only_added_to_text_types_without_one() {
+        let config = FinalizeConfig {
+            charset: Some("utf-8".to_string()),
+            ..FinalizeConfig::default()
+        };
+
+        for (content_type, expected) in [
+            ("text/plain", "text/plain; charset=utf-8"),
+            ("text/html; charset=latin1", "text/html; charset=latin1"),
+            ("application/json", "application/json"),
+            ("image/png", "image/png"),
+        ] {
+            let mut response = Response::new();
+            response.set_header("Content-Type", content_type);
+
+            finalize(&mut response, None, &config);
+
+            assert_eq!(response.get_header("Content-Type"), Some(expected));
+        }
+    }
+
+    fn gzip_request() -> Request {
+        Request::new("GET / HTTP/1.1\r\nHost: x\r\nAccept-Encoding: br, gzip\r\n\r\n").unwrap()
+    }
+
+    fn typed(content_type: &str, body: &str) -> Response {
+        let mut response = Response::new();
+        response.set_header("Content-Type", content_type);
+        response.set_body(body);
+        response
+    }
+
+    #[test]
+    fn only_compressible_types_are_gzipped() {
+        for (content_type, compressed) in [
+            ("text/plain", true),
+            ("application/json", true),
+            ("image/png", false),
+            ("application/zip", false),
+        ] {
+            let mut response = typed(content_type, "hello");
+
+            finalize(
+                &mut response,
+                Some(&gzip_request()),
+                &FinalizeConfig::default(),
+            );
+
+            let encoding = compressed.then_some("gzip");
+            assert_eq!(
+                response.get_header("Content-Encoding"),
+                encoding,
+                "{}",
+                content_type
+            );
+            assert_eq!(response.body() == b"hello", !compressed, "{}", content_type);
+        }
+    }
+
+    #[test]
+    fn a_denylist_compresses_everything_else() {
+        let config = FinalizeConfig {
+            compressible_types: CompressibleTypes::AllBut(vec!["image/*".to_string()]),
+            ..FinalizeConfig::default()
+        };
+        let mut binary = typed("application/octet-stream", "hello");
+        let mut image = typed("image/png", "hello");
+
+        finalize(&mut binary, Some(&gzip_request()), &config);
+        finalize(&mut image, Some(&gzip_request()), &config);
+
+        assert_eq!(binary.get_header("Content-Encoding"), Some("gzip"));
+        assert_eq!(image.get_header("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn bodies_under_the_minimum_size_are_sent_as_they_are() {
+        let config = FinalizeConfig {
+            compress_min_size: 6,
+            ..FinalizeConfig::default()
+        };
+
+        for (body, compressed) in [("hello", false), ("hello!", true), ("", false)] {
+            let mut response = typed("text/plain", body);
+
+            finalize(&mut response, Some(&gzip_request()), &config);
+
+            let encoding = compressed.then_some("gzip");
+            assert_eq!(
+                response.get_header("Content-Encoding"),
+                encoding,
+                "{:?}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn compression_needs_the_client_to_accept_gzip_but_always_varies() {
+        for accept_encoding in [
+            "",
+            "Accept-Encoding: br\r\n",
+            "Accept-Encoding: gzip;q=0\r\n",
+        ] {
+            let req = Request::new(&format!(
+                "GET / HTTP/1.1\r\nHost: x\r\n{}\r\n",
+                accept_encoding
+            ))
+            .unwrap();
+            let mut response = typed("text/plain", "hello");
+
+            finalize(&mut response, Some(&req), &FinalizeConfig::default());
+
+            assert_eq!(
+                response.get_header("Content-Encoding"),
+                None,
+                "{:?}",
+                accept_encoding
+            );
+            assert_eq!(response.get_header("Vary"), Some("Accept-Encoding"));
+            assert_eq!(response.body(), b"hello");
+        }
+    }
+
+    #[test]
+    fn encoded_and_partial_responses_are_left_alone() {
+        let mut encoded = typed("text/plain", "hello");
+        encoded.set_header("Content-Encoding", "br");
+        let mut partial = typed("text/plain", "hello");
+        partial.set_status(StatusCode::PARTIAL_CONTENT);
+        partial.set_header("Content-Range", "bytes 0-4/10");
+
+        finalize(
+            &mut encoded,
+            Some(&gzip_request()),
+            &FinalizeConfig::default(),
+        );
+        finalize(
+            &mut partial,
+            Some(&gzip_request()),
+            &FinalizeConfig::default(),
+        );
+
+        assert_eq!(encoded.get_header("Content-Encoding"), Some("br"));
+        assert_eq!(partial.get_header("Content-Encoding"), None);
+        assert_eq!(partial.body(), b"hello");
+    }
+
+    #[test]
+    fn head_keeps_the_framing_of_the_get() {
+        let head =
+            Request::new("HEAD / HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n").unwrap();
+        let mut response = typed("text/plain", "hello");
+        let mut get = typed("text/plain", "hello");
+
+        finalize(&mut response, Some(&head), &FinalizeConfig::default());
+        finalize(&mut get, Some(&gzip_request()), &FinalizeConfig::default());
+
+        assert_eq!(response.body(), b"");
+        assert_eq!(response.get_header("Content-Encoding"), Some("gzip"));
+        assert_eq!(
+            response.get_header("Content-Length"),
+            Some(get.body().len().to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn compression_can_be_turned_off() {
+        let config = FinalizeConfig {
+            compression: false,
+            ..FinalizeConfig::default()
+        };
+        let mut response = typed("text/plain", "hello");
+
+        finalize(&mut response, Some(&gzip_request()), &config);
+
+        assert_eq!(response.get_header("Content-Encoding"), None);
+        assert_eq!(response.get_header("Vary"), None);
+        assert_eq!(response.body(), b"hello");
+    }
+
+    #[test]
+    fn compress_with_sets_the_encoding_once() {
+        let mut response = typed("text/plain", "hello");
+        response.set_header("Vary", "Origin");
+
+        response.compress_with(Encoding::Gzip);
+        let compressed = response.body().to_vec();
+        response.compress_with(Encoding::Gzip);
+
+        assert_eq!(response.get_header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.get_header("Vary"), Some("Origin, Accept-Encoding"));
+        assert_eq!(
+            response.get_header("Content-Length"),
+            Some(compressed.len().to_string().as_str())
+        );
+        assert_eq!(response.body(), compressed);
+    }
+
+    #[test]
+    fn the_coding_weighted_highest_is_used() {
+        let req = Request::new(
+            "GET / HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip;q=0.5, deflate\r\n\r\n",
+        )
+        .unwrap();
+        let mut response = typed("text/plain", "hello");
+
+        finalize(&mut response, Some(&req), &FinalizeConfig::default());
+
+        assert_eq!(response.get_header("Content-Encoding"), Some("deflate"));
+        assert_eq!(response.body(), Encoding::Deflate.encode(b"hello"));
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{Request, Response};
+
+pub(crate) const INSPECT_PATH: &str = "/__echo";
+
+// Headers whose values are credentials. They are listed with their value
+// replaced, so the output can be shared without leaking them.
+const REDACTED_HEADERS: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+/// Describes the request as the server parsed it, as JSON: method, target,
+/// query parameters, every header in the order received (names lowercased, as
+/// the parser stores them, and credentials redacted) and the body length.
+pub(crate) fn inspect(req: &Request) -> Response {
+    let query = match &req.query {
+        Some(query) => format!("\"{}\"", escape_json(query)),
+        None => "null".to_string(),
+    };
+
+    let json = format!(
+        "{{\"method\":\"{}\",\"path\":\"{}\",\"raw_path\":\"{}\",\"version\":\"{}\",\
+         \"query\":{},\"query_params\":[{}],\"headers\":[{}],\"body_length\":{}}}",
+        req.verb,
+        escape_json(&req.path),
+        escape_json(&req.raw_path),
+        escape_json(&req.version),
+        query,
+        pairs(&req.query_params()),
+        pairs(&redacted_headers(req)),
+        req.body_len()
+    );
+
+    let mut response = Response::new();
+    response.set_header("Content-Type", "application/json");
+    response.set_header("Cache-Control", "no-store");
+    response.set_body(&json);
+    response
+}
+
+// Pairs become two-element arrays rather than object members so duplicates
+// and order survive.
+fn pairs(pairs: &[(String, String)]) -> String {
+    pairs
+        .iter()
+        .map(|(name, value)| format!("[\"{}\",\"{}\"]", escape_json(name), escape_json(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn redacted_headers(req: &Request) -> Vec<(String, String)> {
+    req.headers
+        .iter()
+        .map(|(name, value)| {
+            if REDACTED_HEADERS.contains(&name.as_str()) {
+                (name.clone(), "[redacted]".to_string())
+            } else {
+                (name.clone(), value.clone())
+            }
+        })
+        .collect()
+}
+
+/// Escapes `value` for use inside a JSON string literal.
+pub fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn escape_json_escapes_quotes_backslashes_and_control_characters() {
+        assert_eq!(escape_json("a\"b\\c\nd\u{1}é"), "a\\\"b\\\\c\\nd\\u0001é");
+    }
+}
@@ -0,0 +1,29 @@
+mod access;
+mod auth;
+mod base64;
+mod body;
+mod compress;
+mod content_type;
+mod error;
+mod finalize;
+mod inspect;
+mod pattern;
+mod request;
+mod response;
+mod server;
+mod status;
+mod websocket;
+
+pub use access::{IpRange, IpRangeError};
+pub use auth::{AuthResult, Authenticator, BasicAuth, BearerAuth};
+pub use compress::{CompressibleTypes, Encoding};
+pub use content_type::ContentType;
+pub use error::HttpError;
+pub use inspect::escape_json;
+pub use request::{ParseError, ParseMode, Request, Verb, VersionSource};
+pub use response::Response;
+pub use server::{
+    AsyncHandler, DuplicateRoute, DuplicateRoutes, Favicon, Handler, RequestFilter, Route, Server,
+};
+pub use status::{StatusClass, StatusCode};
+pub use websocket::WebSocketHandler;
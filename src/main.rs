@@ -1,327 +1,787 @@
 use anyhow::Context;
-use anyhow::Result;
-use std::fmt::Display;
-use std::io::Write;
+use http_server_starter_rust::{
+    escape_json, CompressibleTypes, DuplicateRoutes, Favicon, HttpError, IpRange, ParseMode,
+    Request, Response, Route, Server, StatusCode, Verb,
+};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-
-#[derive(Debug)]
-struct Request {
-    verb: Verb,
-    path: String,
-    headers: Vec<(String, String)>,
-    body: String,
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::{self, Receiver};
+
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+fn handle_root(_: &Request) -> Result<Response, HttpError> {
+    Ok(Response::new())
 }
 
-impl Request {
-    fn new(request: &str) -> Result<Request> {
-        let verb = match request.split_whitespace().next() {
-            Some("GET") => Verb::Get,
-            Some("POST") => Verb::Post,
-            _ => return Err(anyhow::anyhow!("Unknown verb")),
-        };
-        let path = request.split_whitespace().nth(1).unwrap_or("/");
-
-        let headers = request
-            .lines()
-            .skip(1)
-            .map(|line| {
-                let mut parts = line.splitn(2, ": ");
-                let key = parts.next().unwrap_or("").to_lowercase();
-                let value = parts.next().unwrap_or("").to_string();
-                (key, value)
-            })
-            .collect();
-
-        let body = request.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
-
-        Ok(Request {
-            verb,
-            path: path.to_string(),
-            headers,
-            body,
-        })
-    }
+fn handle_echo_request(req: &Request) -> Result<Response, HttpError> {
+    let mut response = Response::new();
 
-    fn get_header(&self, key: &str) -> Option<&str> {
-        self.headers
-            .iter()
-            .find(|(k, _)| k == &key.to_lowercase())
-            .map(|(_, v)| v.as_str())
-    }
+    // Echo back exactly what was sent after `/echo/`, escapes and all, rather
+    // than the decoded `message` parameter the route matched on. The query is
+    // not part of the message.
+    let raw_path = req.raw_path.split('?').next().unwrap_or("");
+    let echo_string = raw_path.splitn(3, '/').nth(2).unwrap_or("");
+    response.set_body(echo_string);
+    response.apply_range(req.get_header("Range"));
+
+    Ok(response)
 }
 
-struct Response {
-    status_code: u32,
-    status_text: String,
-    body: String,
-    headers: Vec<(String, String)>,
+fn handle_user_agent_request(req: &Request) -> Result<Response, HttpError> {
+    let mut response = Response::new();
+
+    let user_agent = req.get_header("User-Agent").unwrap_or("Unknown");
+    response.set_body(user_agent);
+
+    Ok(response)
 }
 
-impl Response {
-    fn new() -> Response {
-        Response {
-            status_code: 200,
-            status_text: String::new(),
-            body: String::new(),
-            headers: Vec::new(),
+// Files are looked up on every request, so ones uploaded since startup are
+// served too. Without a directory there are no files to serve, and only
+// names directly inside it are looked up.
+async fn handle_files_request(
+    req: &Request,
+    directory: Option<PathBuf>,
+    listing: Option<PathBuf>,
+) -> Result<Response, HttpError> {
+    let given_file_name = req.param("path").unwrap_or("");
+
+    if given_file_name.is_empty() {
+        if let Some(directory) = listing {
+            return handle_directory_listing(req, &directory).await;
         }
     }
+    check_file_name(given_file_name)?;
+
+    let path = match directory {
+        Some(directory) if !given_file_name.contains('/') => directory.join(given_file_name),
+        _ => return Err(HttpError::NotFound),
+    };
+    let mut file = match tokio::fs::File::open(&path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(HttpError::NotFound),
+        opened => opened.context("problem opening file")?,
+    };
+    let metadata = file.metadata().await.context("problem reading file")?;
+    if !metadata.is_file() {
+        return Err(HttpError::NotFound);
+    }
+
+    let mut response = Response::new();
+    response.set_header("Content-Type", guess_content_type(&path));
 
-    fn set_body(&mut self, body: &str) {
-        self.body = body.to_string();
+    // A range needs the bytes in hand to be cut out of them; everything else
+    // is streamed from the file as it is read.
+    if req.get_header("Range").is_some() {
+        let mut file_contents = Vec::new();
+        file.read_to_end(&mut file_contents)
+            .await
+            .context("problem reading file")?;
+        response.set_body_bytes(&file_contents);
+        response.apply_range(req.get_header("Range"));
+    } else {
+        response.set_header("Content-Length", &metadata.len().to_string());
+        response.set_body_stream(stream_file(file, &path));
     }
 
-    fn set_header(&mut self, key: &str, value: &str) {
-        self.headers.push((key.to_string(), value.to_string()));
+    Ok(response)
+}
+
+// File names come straight from the request path, so anything that could
+// step outside the directory is refused rather than looked up.
+fn check_file_name(file_name: &str) -> Result<(), HttpError> {
+    let escapes = file_name
+        .split('/')
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+        || file_name.contains('\\');
+
+    if escapes {
+        Err(HttpError::BadRequest(format!(
+            "invalid file name {:?}",
+            file_name
+        )))
+    } else {
+        Ok(())
     }
+}
+
+// Sends the file in FILE_CHUNK_SIZE pieces. A read error ends the stream
+// early, which drops the connection as the body falls short of its length.
+fn stream_file(mut file: tokio::fs::File, path: &Path) -> Receiver<Vec<u8>> {
+    let (chunks, receiver) = mpsc::channel(4);
+    let path = path.to_path_buf();
+
+    tokio::spawn(async move {
+        let mut buffer = vec![0; FILE_CHUNK_SIZE];
+        loop {
+            match file.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(len) => {
+                    if chunks.send(buffer[..len].to_vec()).await.is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Problem reading {}: {}", path.display(), err);
+                    break;
+                }
+            }
+        }
+    });
 
-    fn set_status_code(&mut self, status_code: u32) {
-        self.status_code = status_code;
+    receiver
+}
+
+fn guess_content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "xml" => "application/xml",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
     }
+}
+
+struct DirectoryEntry {
+    name: String,
+    size: u64,
+    modified: u64,
+}
 
-    fn set_status_text(&mut self, status_text: &str) {
-        self.status_text = status_text.to_string();
+async fn scan_directory(directory: &Path) -> std::io::Result<Vec<DirectoryEntry>> {
+    let mut entries = Vec::new();
+
+    let mut read_dir = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+
+        entries.push(DirectoryEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            size: metadata.len(),
+            modified,
+        });
     }
 
-    async fn send(&self, stream: &mut TcpStream) {
-        let response = format!("{}", self);
-        stream.write_all(response.as_bytes()).await.unwrap();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+fn accepts_json(req: &Request) -> bool {
+    req.get_header("Accept")
+        .unwrap_or("")
+        .split(',')
+        .map(|media_type| media_type.split(';').next().unwrap_or("").trim())
+        .any(|media_type| media_type.eq_ignore_ascii_case("application/json"))
+}
+
+async fn handle_directory_listing(req: &Request, directory: &Path) -> Result<Response, HttpError> {
+    let entries = scan_directory(directory)
+        .await
+        .context("problem scanning directory")?;
+
+    let (content_type, body) = if accepts_json(req) {
+        ("application/json", directory_listing_json(&entries))
+    } else {
+        ("text/html", directory_listing_html(&entries))
+    };
+
+    let mut response = Response::new();
+    response.set_header("Content-Type", content_type);
+    response.set_body(&body);
+
+    Ok(response)
+}
+
+fn directory_listing_json(entries: &[DirectoryEntry]) -> String {
+    let entries = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"name\":\"{}\",\"size\":{},\"modified\":{}}}",
+                escape_json(&entry.name),
+                entry.size,
+                entry.modified
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!("[{}]", entries.join(","))
+}
+
+fn directory_listing_html(entries: &[DirectoryEntry]) -> String {
+    let mut html =
+        String::from("<!DOCTYPE html>\n<html>\n<head><title>Files</title></head>\n<body>\n<ul>\n");
+
+    for entry in entries {
+        let name = escape_html(&entry.name);
+        html.push_str(&format!(
+            "<li><a href=\"/files/{}\">{}</a> ({} bytes)</li>\n",
+            name, name, entry.size
+        ));
     }
 
-    fn new_404() -> Self {
-        let mut response = Self::new();
-        response.set_status_code(404);
-        response.set_status_text("Not Found");
-        response.set_body("Not Found");
-        response
+    html.push_str("</ul>\n</body>\n</html>\n");
+    html
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
     }
+
+    escaped
 }
 
-impl Display for Response {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut response = format!("HTTP/1.1 {} {}\r\n", self.status_code, self.status_text);
+async fn handle_post_file(req: &Request, directory: PathBuf) -> Result<Response, HttpError> {
+    let file_name = req.param("path").unwrap_or("");
+    check_file_name(file_name)?;
 
-        for (key, value) in &self.headers {
-            response.push_str(&format!("{}: {}\r\n", key, value));
-        }
+    // Large bodies arrive in a temp file, which is moved into place.
+    if let Err(err) = req.save_body(&directory.join(file_name)).await {
+        return storage_error(err, "problem writing file");
+    }
 
-        response.push_str("\r\n");
-        response.push_str(&self.body);
+    Ok(Response::with_status(StatusCode::CREATED))
+}
 
-        write!(f, "{}", response)
+// Errors the client can act on get their own status; anything else is a 500.
+// ENOSPC and EROFS are matched by number as their ErrorKinds need Rust 1.83.
+fn storage_error(err: std::io::Error, context: &str) -> Result<Response, HttpError> {
+    const ENOSPC: i32 = 28;
+    const EROFS: i32 = 30;
+
+    match (err.kind(), err.raw_os_error()) {
+        (_, Some(ENOSPC)) => Ok(Response::error(
+            StatusCode::INSUFFICIENT_STORAGE,
+            "Not enough storage left to save the file",
+        )),
+        (_, Some(EROFS)) => Ok(Response::error(
+            StatusCode::FORBIDDEN,
+            "The file directory is read-only",
+        )),
+        (ErrorKind::PermissionDenied, _) => Ok(Response::error(
+            StatusCode::FORBIDDEN,
+            "Permission denied saving the file",
+        )),
+        _ => Err(anyhow::Error::new(err).context(context.to_string()).into()),
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-enum Verb {
-    Get,
-    Post,
+// Accepts IPv6 addresses with or without the brackets used in URLs.
+fn parse_host(host: &str) -> anyhow::Result<IpAddr> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+
+    unbracketed.parse().map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid --host {:?}: expected an IPv4 address (127.0.0.1) or an IPv6 address ([::1])",
+            host
+        )
+    })
 }
 
-#[derive(Debug)]
-struct Route {
-    path: String,
-    verb: Verb,
+// A comma-separated list such as `text/*,application/json`.
+fn parse_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
 }
 
-impl Route {
-    fn new(path: &str, verb: Verb) -> Self {
-        Self {
-            path: path.to_string(),
-            verb,
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let mut dir = std::env::current_dir()?;
+    let mut directory_given = false;
+    let mut list_directory = false;
+    let mut linger = None;
+    let mut host = IpAddr::from([127, 0, 0, 1]);
+    let mut dual_stack = false;
+    let mut parse_mode = ParseMode::Lenient;
+    let mut max_uploads = None;
+    let mut debug = false;
+    let mut idle_timeout = None;
+    let mut read_timeout = None;
+    let mut favicon = None;
+    let mut log_favicon = true;
+    let mut compressible_types = None;
+    let mut compress_min_size = None;
+    let mut spill_threshold = None;
+    let mut allowed_ips = Vec::new();
+    let mut denied_ips = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--directory" => {
+                dir = PathBuf::from(args.next().context("--directory requires a path")?);
+                directory_given = true;
+            }
+            "--list-directory" => list_directory = true,
+            "--linger" => {
+                let seconds = args.next().context("--linger requires seconds")?;
+                let seconds = seconds.parse().context("--linger requires seconds")?;
+                linger = Some(Duration::from_secs_f64(seconds));
+            }
+            "--host" => host = parse_host(&args.next().context("--host requires an address")?)?,
+            "--dual-stack" => dual_stack = true,
+            "--strict" => parse_mode = ParseMode::Strict,
+            "--debug" => debug = true,
+            "--favicon" => favicon = Some(Favicon::NoContent),
+            "--favicon-path" => {
+                let path = args.next().context("--favicon-path requires a path")?;
+                favicon = Some(Favicon::File(PathBuf::from(path)));
+            }
+            "--quiet-favicon" => log_favicon = false,
+            "--idle-timeout" => {
+                let seconds = args.next().context("--idle-timeout requires seconds")?;
+                let seconds = seconds.parse().context("--idle-timeout requires seconds")?;
+                idle_timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            "--allow" => {
+                let range = args
+                    .next()
+                    .context("--allow requires an address or CIDR range")?;
+                allowed_ips.push(range.parse::<IpRange>()?);
+            }
+            "--deny" => {
+                let range = args
+                    .next()
+                    .context("--deny requires an address or CIDR range")?;
+                denied_ips.push(range.parse::<IpRange>()?);
+            }
+            "--read-timeout" => {
+                let seconds = args.next().context("--read-timeout requires seconds")?;
+                let seconds = seconds.parse().context("--read-timeout requires seconds")?;
+                read_timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            "--max-uploads" => {
+                let limit = args.next().context("--max-uploads requires a number")?;
+                max_uploads = Some(limit.parse().context("--max-uploads requires a number")?);
+            }
+            "--compress-types" => {
+                let types = args.next().context("--compress-types requires a list")?;
+                compressible_types = Some(CompressibleTypes::Only(parse_list(&types)));
+            }
+            "--skip-compress-types" => {
+                let types = args
+                    .next()
+                    .context("--skip-compress-types requires a list")?;
+                compressible_types = Some(CompressibleTypes::AllBut(parse_list(&types)));
+            }
+            "--compress-min-size" => {
+                let size = args.next().context("--compress-min-size requires bytes")?;
+                compress_min_size =
+                    Some(size.parse().context("--compress-min-size requires bytes")?);
+            }
+            "--spill-threshold" => {
+                let size = args.next().context("--spill-threshold requires bytes")?;
+                spill_threshold = Some(size.parse().context("--spill-threshold requires bytes")?);
+            }
+            _ => return Err(anyhow::anyhow!("Unknown argument: {}", arg)),
         }
     }
 
-    fn does_match(&self, req: &Request) -> bool {
-        self.verb == req.verb && req.path.starts_with(&self.path)
+    if dual_stack && host.is_ipv4() {
+        return Err(anyhow::anyhow!(
+            "--dual-stack requires an IPv6 --host such as [::]"
+        ));
     }
-}
 
-type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
-struct Server {
-    tcp_listener: TcpListener,
-    root_handler: Option<Handler>,
-    routes: Vec<(Route, Handler)>,
+    let mut server = Server::new(&SocketAddr::new(host, 4221).to_string()).await?;
+    server.set_graceful_close(linger);
+    server.set_duplicate_routes(DuplicateRoutes::Error);
+    server.set_dual_stack(dual_stack);
+    server.set_parse_mode(parse_mode);
+    server.set_max_concurrent_uploads(max_uploads);
+    server.set_debug(debug);
+    server.set_idle_timeout(idle_timeout);
+    if let Some(read_timeout) = read_timeout {
+        server.set_read_timeout(Some(read_timeout));
+    }
+    server.set_log_idle_closures(debug);
+    server.set_favicon(favicon);
+    server.set_log_favicon(log_favicon);
+    if let Some(types) = compressible_types {
+        server.set_compressible_types(types);
+    }
+    if let Some(min_size) = compress_min_size {
+        server.set_compress_min_size(min_size);
+    }
+    if let Some(threshold) = spill_threshold {
+        server.set_spill_threshold(Some(threshold));
+    }
+    for range in allowed_ips {
+        server.add_allowed_ips(range);
+    }
+    for range in denied_ips {
+        server.add_denied_ips(range);
+    }
+
+    server.set_root_handler(Box::new(handle_root));
+    server.register_route(
+        Route::new("/echo/:message", Verb::Get).with_content_type("text/plain"),
+        Box::new(handle_echo_request),
+    )?;
+    server.register_route(
+        Route::new("/user-agent", Verb::Get).with_content_type("text/plain"),
+        Box::new(handle_user_agent_request),
+    )?;
+
+    let files = directory_given.then(|| dir.clone());
+    let listing = list_directory.then(|| dir.clone());
+    server.register_async_route(
+        Route::new("/files/*path", Verb::Get),
+        Box::new(move |req| Box::pin(handle_files_request(req, files.clone(), listing.clone()))),
+    )?;
+
+    server.register_async_route(
+        Route::new("/files/*path", Verb::Post).as_upload(),
+        Box::new(move |req| Box::pin(handle_post_file(req, dir.clone()))),
+    )?;
+
+    // Ctrl-C stops new connections; the ones already open are answered first.
+    let arc_server = Arc::new(server);
+    Server::listen_until(arc_server, async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+    .await?;
+
+    Ok(())
 }
 
-impl Server {
-    async fn new(addr: &str) -> Self {
-        let tcp_listener = TcpListener::bind(addr).await.unwrap();
-        let routes = Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    // A fresh, empty directory under the system temp directory holding
+    // `files`, unique to this test.
+    fn test_directory(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("http-server-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+
+        for (file_name, contents) in files {
+            std::fs::write(directory.join(file_name), contents).unwrap();
+        }
+        directory
+    }
 
-        Self {
-            tcp_listener,
-            root_handler: None,
-            routes,
+    // Handlers are called directly here, so the `/files/*path` parameter the
+    // router would capture is filled in by hand.
+    fn request(raw: &str) -> Request {
+        let mut req = Request::new(raw).unwrap();
+        if let Some(path) = req.path.strip_prefix("/files/") {
+            req.params = vec![("path".to_string(), path.to_string())];
         }
+        req
     }
 
-    fn register_route(&mut self, route: Route, handler: Handler) {
-        self.routes.push((route, handler));
+    fn body(response: &Response) -> String {
+        String::from_utf8(response.body().to_vec()).unwrap()
     }
 
-    fn set_root_handler(&mut self, handler: Handler) {
-        self.root_handler = Some(handler);
+    #[tokio::test]
+    async fn directory_listing_is_html_by_default() {
+        let directory = test_directory("listing-html", &[("b.txt", b"bb"), ("<a>.txt", b"a")]);
+        let req = request("GET /files/ HTTP/1.1\r\nAccept: text/html\r\n\r\n");
+
+        let response = handle_directory_listing(&req, &directory).await.unwrap();
+
+        assert_eq!(response.get_header("Content-Type"), Some("text/html"));
+        let body = body(&response);
+        assert!(body.contains(
+            "<li><a href=\"/files/&lt;a&gt;.txt\">&lt;a&gt;.txt</a> (1 bytes)</li>\n\
+             <li><a href=\"/files/b.txt\">b.txt</a> (2 bytes)</li>"
+        ));
     }
 
-    async fn listen(self: Arc<Self>) -> Result<()> {
-        loop {
-            let (mut stream, _) = self
-                .tcp_listener
-                .accept()
-                .await
-                .context("Error accepting")?;
-
-            tokio::spawn({
-                let me = Arc::clone(&self);
-                async move {
-                    let _ = me.handle_connection(&mut stream).await;
-                }
-            });
-        }
+    #[tokio::test]
+    async fn directory_listing_is_json_when_accepted() {
+        let directory = test_directory("listing-json", &[("b.txt", b"bb"), ("a\"q.txt", b"a")]);
+        let req =
+            request("GET /files/ HTTP/1.1\r\nAccept: text/html;q=0.9, application/json\r\n\r\n");
+
+        let response = handle_directory_listing(&req, &directory).await.unwrap();
+
+        assert_eq!(
+            response.get_header("Content-Type"),
+            Some("application/json")
+        );
+        let body = body(&response);
+        assert!(body.starts_with("[{\"name\":\"a\\\"q.txt\",\"size\":1,\"modified\":"));
+        assert!(body.contains("},{\"name\":\"b.txt\",\"size\":2,\"modified\":"));
+        assert!(body.ends_with("}]"));
     }
 
-    async fn handle_connection(&self, tcp_stream: &mut TcpStream) -> Result<()> {
-        let mut buf = [0; 4096];
-        let bytes_read = tcp_stream
-            .read(&mut buf)
-            .await
-            .context("problem reading into buffer")?;
+    #[test]
+    fn echo_keeps_escapes_but_not_the_query() {
+        let req = request("GET /echo/a%20b?x=1 HTTP/1.1\r\n\r\n");
 
-        let req = Request::new(&String::from_utf8_lossy(&buf[0..bytes_read]));
+        let response = handle_echo_request(&req).unwrap();
 
-        let req = req.context("problem parsing request")?;
+        assert_eq!(body(&response), "a%20b");
+    }
 
-        if req.path == "/" {
-            if let Some(root_handler) = &self.root_handler {
-                root_handler(&req).send(tcp_stream).await;
-                return Ok(());
-            } else {
-                let response = Response::new_404();
-                response.send(tcp_stream).await;
-                return Ok(());
-            }
-        }
+    fn echo_range(range: &str) -> Response {
+        let req = request(&format!(
+            "GET /echo/abcdef HTTP/1.1\r\nRange: {}\r\n\r\n",
+            range
+        ));
+        handle_echo_request(&req).unwrap()
+    }
 
-        if let Some((_, handler)) = self.routes.iter().find(|(route, _)| route.does_match(&req)) {
-            let response = handler(&req);
+    #[test]
+    fn echo_serves_the_requested_range() {
+        let response = echo_range("bytes=1-3");
 
-            response.send(tcp_stream).await;
-        } else {
-            let response = Response::new_404();
-            response.send(tcp_stream).await;
-            return Ok(());
-        }
+        assert_eq!(response.status_code(), 206);
+        assert_eq!(response.get_header("Content-Range"), Some("bytes 1-3/6"));
+        assert_eq!(body(&response), "bcd");
 
-        Ok(())
+        assert_eq!(body(&echo_range("bytes=4-")), "ef");
+        assert_eq!(body(&echo_range("bytes=-2")), "ef");
+        assert_eq!(body(&echo_range("bytes=2-100")), "cdef");
     }
-}
 
-fn handle_root(_: &Request) -> Response {
-    Response::new()
-}
+    #[test]
+    fn echo_range_past_the_end_is_not_satisfiable() {
+        let response = echo_range("bytes=6-");
 
-fn handle_echo_request(req: &Request) -> Response {
-    let mut response = Response::new();
+        assert_eq!(response.status_code(), 416);
+        assert_eq!(response.get_header("Content-Range"), Some("bytes */6"));
+        assert_eq!(body(&response), "");
+    }
 
-    let echo_string = req.path.strip_prefix("/echo/").unwrap_or("");
-    response.set_header("Content-Type", "text/plain");
-    response.set_header("Content-Length", &echo_string.len().to_string());
-    response.set_body(echo_string);
+    #[test]
+    fn echo_ignores_ranges_it_does_not_understand() {
+        let response = echo_range("bytes=0-1,3-4");
 
-    response
-}
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(body(&response), "abcdef");
+    }
 
-fn handle_user_agent_request(req: &Request) -> Response {
-    let mut response = Response::new();
+    #[tokio::test]
+    async fn uploads_are_saved_under_their_file_name() {
+        let directory = test_directory("upload", &[]);
+        let req = request("POST /files/note.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
 
-    let user_agent = req.get_header("User-Agent").unwrap_or("Unknown");
-    response.set_header("Content-Type", "text/plain");
-    response.set_header("Content-Length", &user_agent.len().to_string());
-    response.set_body(user_agent);
+        let response = handle_post_file(&req, directory.clone()).await.unwrap();
 
-    response
-}
+        assert_eq!(response.status_code(), 201);
+        assert_eq!(std::fs::read(directory.join("note.txt")).unwrap(), b"hello");
+    }
 
-fn handle_files_request(req: &Request, files: &[PathBuf]) -> Response {
-    let given_file_name = req.path.strip_prefix("/files/").unwrap_or("");
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_full_disk_is_insufficient_storage() {
+        let err = std::io::Error::from_raw_os_error(28);
 
-    if let Some(file) = files
-        .iter()
-        .find(|file| file.file_name().unwrap_or_default() == given_file_name)
-    {
-        let mut response = Response::new();
-        let file_contents = std::fs::read_to_string(file).unwrap_or_default();
+        let response = storage_error(err, "problem writing file").unwrap();
 
-        response.set_header("Content-Type", "application/octet-stream");
-        response.set_header("Content-Length", &file_contents.len().to_string());
-        response.set_body(&file_contents);
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(body(&response), "Not enough storage left to save the file");
+    }
 
-        response
-    } else {
-        Response::new_404()
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_read_only_filesystem_is_forbidden() {
+        let err = std::io::Error::from_raw_os_error(30);
+
+        let response = storage_error(err, "problem writing file").unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body(&response), "The file directory is read-only");
     }
-}
 
-fn handle_post_file(req: &Request, directory: &Path) -> Response {
-    let file_name = req.path.strip_prefix("/files/").unwrap_or("");
-    let body_bytes = req.body.as_bytes();
+    #[test]
+    fn permission_denied_is_forbidden() {
+        let err = std::io::Error::from(ErrorKind::PermissionDenied);
 
-    let mut file = std::fs::File::create(directory.join(file_name)).unwrap();
-    file.write_all(body_bytes).unwrap();
+        let response = storage_error(err, "problem writing file").unwrap();
 
-    // FIXME: Create a response with a given status code
-    let mut response = Response::new();
-    response.set_status_code(201);
-    response
-}
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body(&response), "Permission denied saving the file");
+    }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = std::env::args().collect::<Vec<_>>();
-    let mut files = Vec::new();
-    let mut dir = std::env::current_dir()?;
-    if args.len() == 3 && args[1] == "--directory" {
-        dir = PathBuf::from(&args[2]);
-        let dir_contents = std::fs::read_dir(&args[2])?;
-
-        for entry in dir_contents {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                files.push(path);
-            }
-        }
+    #[test]
+    fn other_storage_errors_are_internal() {
+        let err = std::io::Error::other("disk on fire");
+
+        let result = storage_error(err, "problem writing file");
+
+        assert!(matches!(result, Err(HttpError::Internal(_))));
     }
 
-    let mut server = Server::new("127.0.0.1:4221").await;
+    // Sends `response` over a loopback connection and returns the body read
+    // at the other end, so that streamed bodies can be checked too.
+    async fn sent_body(mut response: Response) -> Vec<u8> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
 
-    server.set_root_handler(Box::new(handle_root));
-    server.register_route(
-        Route::new("/echo", Verb::Get),
-        Box::new(handle_echo_request),
-    );
-    server.register_route(
-        Route::new("/user-agent", Verb::Get),
-        Box::new(handle_user_agent_request),
-    );
+        response.send(&mut server).await.unwrap();
+        drop(server);
 
-    server.register_route(
-        Route::new("/files", Verb::Get),
-        Box::new(move |req| handle_files_request(req, &files)),
-    );
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).await.unwrap();
+        let head_end = sent.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        sent.split_off(head_end + 4)
+    }
 
-    server.register_route(
-        Route::new("/files", Verb::Post),
-        Box::new(move |req| handle_post_file(req, &dir)),
-    );
+    // The file handlers only wait on tokio::fs, so they also run on a
+    // single-threaded runtime, where blocking would stall every connection.
+    #[tokio::test(flavor = "current_thread")]
+    async fn uploaded_files_can_be_fetched_back_byte_for_byte() {
+        let directory = test_directory("round-trip", &[]);
+        let contents: Vec<u8> = (0..200 * 1024).map(|i| (i * 7 % 256) as u8).collect();
+        let mut upload = request(&format!(
+            "POST /files/big.bin HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            contents.len()
+        ));
+        upload.body = contents.clone();
+
+        let created = handle_post_file(&upload, directory.clone()).await.unwrap();
+        assert_eq!(created.status_code(), 201);
+
+        let req = request("GET /files/big.bin HTTP/1.1\r\n\r\n");
+        let response = handle_files_request(&req, Some(directory.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.get_header("Content-Type"),
+            Some("application/octet-stream")
+        );
+        assert_eq!(
+            response.get_header("Content-Length"),
+            Some(contents.len().to_string().as_str())
+        );
+        assert!(sent_body(response).await == contents, "the file changed");
+
+        let req = request("GET /files/big.bin HTTP/1.1\r\nRange: bytes=100-103\r\n\r\n");
+        let response = handle_files_request(&req, Some(directory), None)
+            .await
+            .unwrap();
+        assert_eq!(response.status_code(), 206);
+        assert_eq!(response.body(), &contents[100..104]);
+    }
 
-    let arc_server = Arc::new(server);
-    Server::listen(arc_server).await?;
+    #[tokio::test]
+    async fn only_files_directly_in_the_directory_are_found() {
+        let directory = test_directory("missing", &[("a.txt", b"a")]);
+        std::fs::create_dir(directory.join("sub")).unwrap();
+        std::fs::write(directory.join("sub").join("b.txt"), b"b").unwrap();
+
+        for path in ["/files/nope.txt", "/files/sub", "/files/sub/b.txt"] {
+            let req = request(&format!("GET {} HTTP/1.1\r\n\r\n", path));
+            assert!(
+                matches!(
+                    handle_files_request(&req, Some(directory.clone()), None).await,
+                    Err(HttpError::NotFound)
+                ),
+                "{}",
+                path
+            );
+        }
 
-    Ok(())
+        let req = request("GET /files/a.txt HTTP/1.1\r\n\r\n");
+        assert!(matches!(
+            handle_files_request(&req, None, None).await,
+            Err(HttpError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn file_names_that_leave_the_directory_are_refused() {
+        let directory = test_directory("traversal", &[]);
+
+        for name in ["..", "../etc/passwd", "a/./b", "a//b", "..\\x"] {
+            let get = request(&format!("GET /files/{} HTTP/1.1\r\n\r\n", name));
+            assert!(
+                matches!(
+                    handle_files_request(&get, Some(directory.clone()), None).await,
+                    Err(HttpError::BadRequest(_))
+                ),
+                "{}",
+                name
+            );
+
+            let post = request(&format!("POST /files/{} HTTP/1.1\r\n\r\n", name));
+            assert!(
+                matches!(
+                    handle_post_file(&post, directory.clone()).await,
+                    Err(HttpError::BadRequest(_))
+                ),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn content_types_are_guessed_from_the_extension() {
+        assert_eq!(guess_content_type(Path::new("a/page.HTML")), "text/html");
+        assert_eq!(guess_content_type(Path::new("photo.jpeg")), "image/jpeg");
+        assert_eq!(
+            guess_content_type(Path::new("archive.tar.gz")),
+            "application/gzip"
+        );
+        assert_eq!(
+            guess_content_type(Path::new("README")),
+            "application/octet-stream"
+        );
+    }
 }
@@ -0,0 +1,191 @@
+/// A route path such as `/echo/:message` or `/files/*path`, matched one
+/// `/`-separated segment at a time:
+///
+/// | Segment | Matches                                            |
+/// |---------|----------------------------------------------------|
+/// | `echo`  | exactly `echo`                                     |
+/// | `:name` | any one non-empty segment, captured as `name`      |
+/// | `*name` | the rest of the path, possibly empty, as `name`    |
+#[derive(Debug, Clone)]
+pub(crate) struct PathPattern {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+impl PathPattern {
+    /// # Panics
+    ///
+    /// If a `*name` segment is not the last one.
+    pub(crate) fn parse(pattern: &str) -> Self {
+        let segments: Vec<Segment> = split(pattern)
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Wildcard(name.to_string())
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect();
+
+        let wildcard = segments
+            .iter()
+            .position(|segment| matches!(segment, Segment::Wildcard(_)));
+        if wildcard.is_some_and(|index| index != segments.len() - 1) {
+            panic!("wildcard must be the last segment of route {:?}", pattern);
+        }
+
+        Self { segments }
+    }
+
+    /// The captured parameters, if `path` matches.
+    pub(crate) fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut rest = split(path);
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    if rest.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => match rest.next() {
+                    Some(value) if !value.is_empty() => {
+                        params.push((name.clone(), value.to_string()));
+                    }
+                    _ => return None,
+                },
+                Segment::Wildcard(name) => {
+                    params.push((name.clone(), rest.collect::<Vec<_>>().join("/")));
+                    return Some(params);
+                }
+            }
+        }
+
+        rest.next().is_none().then_some(params)
+    }
+
+    /// Orders patterns from least to most specific, for choosing between
+    /// several that match the same path. Patterns without a wildcard beat
+    /// those with one, then segments are compared left to right with a
+    /// literal beating a parameter beating a wildcard.
+    pub(crate) fn specificity(&self) -> (bool, Vec<u8>) {
+        let ranks = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(_) => 2,
+                Segment::Param(_) => 1,
+                Segment::Wildcard(_) => 0,
+            })
+            .collect();
+
+        (!self.has_wildcard(), ranks)
+    }
+
+    /// Whether both patterns match exactly the same paths, whatever their
+    /// parameters are called.
+    pub(crate) fn is_equivalent(&self, other: &PathPattern) -> bool {
+        self.segments.len() == other.segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(&other.segments)
+                .all(|pair| match pair {
+                    (Segment::Literal(a), Segment::Literal(b)) => a == b,
+                    (Segment::Param(_), Segment::Param(_)) => true,
+                    (Segment::Wildcard(_), Segment::Wildcard(_)) => true,
+                    _ => false,
+                })
+    }
+
+    fn has_wildcard(&self) -> bool {
+        matches!(self.segments.last(), Some(Segment::Wildcard(_)))
+    }
+}
+
+fn split(path: &str) -> std::str::Split<'_, char> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn params(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        PathPattern::parse(pattern).matches(path)
+    }
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn literals_match_exactly() {
+        assert_eq!(params("/user-agent", "/user-agent"), Some(vec![]));
+        assert_eq!(params("/user-agent", "/user-agent/"), None);
+        assert_eq!(params("/user-agent", "/user"), None);
+    }
+
+    #[test]
+    fn params_capture_one_non_empty_segment() {
+        assert_eq!(
+            params("/users/:id/posts/:post", "/users/7/posts/9"),
+            Some(vec![pair("id", "7"), pair("post", "9")])
+        );
+        assert_eq!(params("/users/:id", "/users/"), None);
+        assert_eq!(params("/users/:id", "/users/7/posts"), None);
+    }
+
+    #[test]
+    fn wildcards_capture_the_rest() {
+        assert_eq!(
+            params("/files/*path", "/files/a/b.txt"),
+            Some(vec![pair("path", "a/b.txt")])
+        );
+        assert_eq!(
+            params("/files/*path", "/files/"),
+            Some(vec![pair("path", "")])
+        );
+        assert_eq!(
+            params("/files/*path", "/files"),
+            Some(vec![pair("path", "")])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be the last segment")]
+    fn wildcard_must_come_last() {
+        PathPattern::parse("/files/*path/edit");
+    }
+
+    #[test]
+    fn literals_beat_params_beat_wildcards() {
+        let specificity = |pattern| PathPattern::parse(pattern).specificity();
+
+        assert!(specificity("/users/me") > specificity("/users/:id"));
+        assert!(specificity("/users/:id") > specificity("/users/*rest"));
+        assert!(specificity("/:section/:id") > specificity("/users/*rest"));
+        assert!(specificity("/users/:id/edit") > specificity("/users/:id/:action"));
+    }
+
+    #[test]
+    fn equivalence_ignores_parameter_names() {
+        let equivalent = |a, b| PathPattern::parse(a).is_equivalent(&PathPattern::parse(b));
+
+        assert!(equivalent("/users/:id", "/users/:name"));
+        assert!(equivalent("/files/*path", "/files/*rest"));
+        assert!(!equivalent("/users/:id", "/users/me"));
+        assert!(!equivalent("/users/:id", "/users/*id"));
+        assert!(!equivalent("/users/:id", "/users/:id/edit"));
+    }
+}
@@ -0,0 +1,672 @@
+use std::fmt::Display;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+use crate::body::SpilledBody;
+use crate::ContentType;
+
+/// How strictly requests are held to RFC 9112. `Lenient` accepts the sloppy
+/// input real clients and hand-written test requests send; `Strict` rejects it
+/// so the request is answered with 400 instead.
+///
+/// | Input                                  | Lenient                          | Strict   |
+/// |----------------------------------------|----------------------------------|----------|
+/// | Lines ending in a bare LF              | accepted                         | rejected |
+/// | Folded header lines (obs-fold)         | joined onto the previous value   | rejected |
+/// | HTTP/1.1 request without a Host header | accepted                         | rejected |
+/// | Request line without a version         | treated as HTTP/1.1              | rejected |
+/// | Header line without a `name:`          | ignored                          | rejected |
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    #[default]
+    Lenient,
+    Strict,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("empty request")]
+    Empty,
+    #[error("unknown verb {0:?}")]
+    UnknownVerb(String),
+    #[error("malformed request line {0:?}")]
+    MalformedRequestLine(String),
+    #[error("line ending without a carriage return")]
+    BareLineFeed,
+    #[error("folded header line")]
+    ObsFold,
+    #[error("malformed header line {0:?}")]
+    MalformedHeader(String),
+    #[error("HTTP/1.1 request without a Host header")]
+    MissingHost,
+    #[error("more than one Host header")]
+    DuplicateHost,
+    #[error("request target decodes to a control character")]
+    ControlCharacter,
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub verb: Verb,
+    /// The percent-decoded path, without the query string. This is what
+    /// routes are matched against. `%2F` stays encoded so an escaped slash
+    /// can never introduce a new path segment.
+    pub path: String,
+    /// The request target exactly as the client sent it, query included.
+    pub raw_path: String,
+    /// The raw query string after `?`, if the target had one.
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    /// Empty when the body was too large to keep in memory and went to a temp
+    /// file instead (see [`Server::set_spill_threshold`]);
+    /// [`body_reader`](Self::body_reader) reads it either way.
+    ///
+    /// [`Server::set_spill_threshold`]: crate::Server::set_spill_threshold
+    pub body: Vec<u8>,
+    spilled: Option<SpilledBody>,
+    /// The values captured by the route's `:name` and `*name` segments, set
+    /// by the server once a route has been chosen.
+    pub params: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn new(request: &str) -> Result<Request, ParseError> {
+        Self::parse(request, ParseMode::Lenient)
+    }
+
+    /// Empty lines before the request line are skipped, as RFC 9112 section
+    /// 2.2 asks. A request with no header lines at all is fine, apart from
+    /// the missing Host that `Strict` rejects for HTTP/1.1.
+    pub fn parse(request: &str, mode: ParseMode) -> Result<Request, ParseError> {
+        Self::parse_bytes(request.as_bytes(), mode)
+    }
+
+    /// Like [`parse`](Self::parse), for a request as read off the wire. The
+    /// body is kept byte for byte; invalid UTF-8 in the head is replaced.
+    pub fn parse_bytes(request: &[u8], mode: ParseMode) -> Result<Request, ParseError> {
+        let skipped = request
+            .iter()
+            .take_while(|&&byte| byte == b'\r' || byte == b'\n')
+            .count();
+        let request = &request[skipped..];
+        let (head, body) = match head_end(request) {
+            Some(head_end) => request.split_at(head_end),
+            None => (request, &[][..]),
+        };
+        let head = String::from_utf8_lossy(head);
+        let head = head.as_ref();
+
+        let mut lines = split_lines(head, mode)?.into_iter();
+        let request_line = lines.next().ok_or(ParseError::Empty)?;
+        let (verb, raw_path, version) = parse_request_line(request_line, mode)?;
+
+        let (path, query) = match raw_path.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (raw_path, None),
+        };
+
+        let path = decode_path(path)?;
+        // Checked up front so that handlers can rely on query_params never
+        // having to drop a pair.
+        for pair in query.as_deref().unwrap_or("").split('&') {
+            decode_query_component(pair)?;
+        }
+
+        let headers = parse_headers(lines, mode)?;
+
+        // RFC 9112 section 3.2: a request with more than one Host is always
+        // rejected, whatever the parse mode, as servers and proxies may each
+        // pick a different one.
+        if headers.iter().filter(|(key, _)| key == "host").count() > 1 {
+            return Err(ParseError::DuplicateHost);
+        }
+
+        let req = Request {
+            verb,
+            path,
+            raw_path: raw_path.to_string(),
+            query,
+            version: version.to_string(),
+            headers,
+            body: body.to_vec(),
+            spilled: None,
+            params: Vec::new(),
+        };
+
+        if mode == ParseMode::Strict
+            && req.version == "HTTP/1.1"
+            && req.get_header("Host").is_none()
+        {
+            return Err(ParseError::MissingHost);
+        }
+
+        Ok(req)
+    }
+
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == &key.to_lowercase())
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub(crate) fn set_spilled_body(&mut self, body: SpilledBody) {
+        self.body.clear();
+        self.spilled = Some(body);
+    }
+
+    /// The length of the body in bytes, wherever it is kept.
+    pub fn body_len(&self) -> usize {
+        match &self.spilled {
+            Some(spilled) => spilled.len(),
+            None => self.body.len(),
+        }
+    }
+
+    /// Reads the body from memory or from the temp file it was spilled to.
+    pub fn body_reader(&self) -> std::io::Result<Box<dyn Read + '_>> {
+        match &self.spilled {
+            Some(spilled) => Ok(Box::new(std::fs::File::open(spilled.path())?)),
+            None => Ok(Box::new(Cursor::new(self.body.as_slice()))),
+        }
+    }
+
+    /// The temp file holding the body, if it was spilled to one. It is
+    /// removed once the request is dropped.
+    pub fn spilled_body_path(&self) -> Option<&Path> {
+        self.spilled.as_ref().map(SpilledBody::path)
+    }
+
+    /// Stores the body at `path`. A spilled body is moved there rather than
+    /// copied where the filesystem allows, so it can only be saved once. A
+    /// write that fails part way leaves no truncated file behind.
+    pub async fn save_body(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = match &self.spilled {
+            Some(spilled) => return spilled.persist(path).await,
+            None => tokio::fs::File::create(path).await?,
+        };
+
+        // tokio hands writes to a background thread, so errors such as a full
+        // disk may only show up once the file is flushed.
+        let written = match file.write_all(&self.body).await {
+            Ok(()) => file.flush().await,
+            Err(err) => Err(err),
+        };
+        if written.is_err() {
+            drop(file);
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        written
+    }
+
+    /// The path segment the route captured as `name`, taken from the decoded
+    /// [`path`](Self::path).
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the client wants the connection kept open after this request:
+    /// HTTP/1.1 unless it sent `Connection: close`, HTTP/1.0 only if it sent
+    /// `Connection: keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.get_header("Connection");
+        if self.version == "HTTP/1.0" {
+            has_token(connection, "keep-alive")
+        } else {
+            !has_token(connection, "close")
+        }
+    }
+
+    /// The parsed `Content-Type` header, if there is one and it is well
+    /// formed.
+    pub fn content_type(&self) -> Option<ContentType> {
+        ContentType::parse(self.get_header("Content-Type")?)
+    }
+
+    /// The API version the client asked for through `?v=` or a `version`
+    /// parameter on `Accept`, in that order, or 1 if it asked for none. Use
+    /// [`api_version_from`](Self::api_version_from) to look elsewhere.
+    pub fn api_version(&self) -> u32 {
+        self.api_version_from(&[
+            VersionSource::Query("v"),
+            VersionSource::AcceptParameter("version"),
+        ])
+    }
+
+    /// The first version found in `sources`, or 1. Versions are whole
+    /// numbers, optionally written with a leading `v` (`2` or `v2`); values
+    /// that are not are skipped.
+    pub fn api_version_from(&self, sources: &[VersionSource]) -> u32 {
+        sources
+            .iter()
+            .find_map(|source| {
+                let values: Vec<String> = match source {
+                    VersionSource::Query(name) => self
+                        .query_params()
+                        .into_iter()
+                        .filter(|(key, _)| key == name)
+                        .map(|(_, value)| value)
+                        .collect(),
+                    VersionSource::Header(name) => self
+                        .headers
+                        .iter()
+                        .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                        .map(|(_, value)| value.clone())
+                        .collect(),
+                    VersionSource::AcceptParameter(name) => self
+                        .get_header("Accept")
+                        .unwrap_or("")
+                        .split(',')
+                        .filter_map(ContentType::parse)
+                        .filter_map(|media_range| media_range.param(name).map(str::to_string))
+                        .collect(),
+                };
+
+                values.iter().find_map(|value| parse_version(value))
+            })
+            .unwrap_or(1)
+    }
+
+    /// The query string split into decoded `name=value` pairs, in order and
+    /// keeping duplicates. `+` decodes to a space and a pair without `=` has
+    /// an empty value. Pairs that decode to control characters are left out,
+    /// though [`parse`](Self::parse) already rejects requests containing them.
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        self.query
+            .as_deref()
+            .unwrap_or("")
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Some((
+                    decode_query_component(name).ok()?,
+                    decode_query_component(value).ok()?,
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Where [`Request::api_version_from`] looks for a version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionSource<'a> {
+    /// A query parameter, e.g. `Query("v")` for `/api?v=2`.
+    Query(&'a str),
+    /// A header of its own, e.g. `Header("Api-Version")`.
+    Header(&'a str),
+    /// A parameter on any media range in `Accept`, e.g.
+    /// `AcceptParameter("version")` for `application/json; version=2`.
+    AcceptParameter(&'a str),
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verb {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Head,
+    Options,
+    /// Parsed so it gets a clear 501 rather than the generic unknown-verb
+    /// one. Its target is an authority (`host:port`), not a path.
+    Connect,
+}
+
+impl Display for Verb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verb = match self {
+            Verb::Get => "GET",
+            Verb::Post => "POST",
+            Verb::Put => "PUT",
+            Verb::Delete => "DELETE",
+            Verb::Head => "HEAD",
+            Verb::Options => "OPTIONS",
+            Verb::Connect => "CONNECT",
+        };
+        write!(f, "{}", verb)
+    }
+}
+
+/// The offset just past the blank line that ends the request head. A bare
+/// `\n\n` counts too so lenient parsing can accept it; strict parsing rejects
+/// the bare line feeds afterwards.
+pub(crate) fn head_end(request: &[u8]) -> Option<usize> {
+    request
+        .iter()
+        .enumerate()
+        .filter(|(_, &byte)| byte == b'\n')
+        .find_map(|(i, _)| match request.get(i + 1..) {
+            Some([b'\n', ..]) => Some(i + 2),
+            Some([b'\r', b'\n', ..]) => Some(i + 3),
+            _ => None,
+        })
+}
+
+// Splits the head into lines without their line endings, dropping the blank
+// line that terminates it.
+fn split_lines(head: &str, mode: ParseMode) -> Result<Vec<&str>, ParseError> {
+    let mut lines = Vec::new();
+
+    for line in head.split_inclusive('\n') {
+        let line = match line.strip_suffix("\r\n") {
+            Some(line) => line,
+            None if mode == ParseMode::Strict && line.ends_with('\n') => {
+                return Err(ParseError::BareLineFeed);
+            }
+            None => line.strip_suffix('\n').unwrap_or(line),
+        };
+
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    Ok(lines)
+}
+
+fn parse_request_line(line: &str, mode: ParseMode) -> Result<(Verb, &str, &str), ParseError> {
+    let parts: Vec<&str> = match mode {
+        ParseMode::Lenient => line.split_whitespace().collect(),
+        ParseMode::Strict => line.split(' ').collect(),
+    };
+
+    let (verb, raw_path, version) = match (mode, parts.as_slice()) {
+        (_, [verb, raw_path, version]) if version.starts_with("HTTP/") => {
+            (*verb, *raw_path, *version)
+        }
+        (ParseMode::Lenient, [verb, raw_path]) => (*verb, *raw_path, "HTTP/1.1"),
+        (ParseMode::Lenient, [verb]) => (*verb, "/", "HTTP/1.1"),
+        _ => return Err(ParseError::MalformedRequestLine(line.to_string())),
+    };
+
+    let verb = match verb {
+        "GET" => Verb::Get,
+        "POST" => Verb::Post,
+        "PUT" => Verb::Put,
+        "DELETE" => Verb::Delete,
+        "HEAD" => Verb::Head,
+        "OPTIONS" => Verb::Options,
+        "CONNECT" => Verb::Connect,
+        _ => return Err(ParseError::UnknownVerb(verb.to_string())),
+    };
+
+    Ok((verb, raw_path, version))
+}
+
+fn parse_headers<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    mode: ParseMode,
+) -> Result<Vec<(String, String)>, ParseError> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+
+    for line in lines {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if mode == ParseMode::Strict {
+                return Err(ParseError::ObsFold);
+            }
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+
+        match line.split_once(':') {
+            Some((key, value)) if is_token(key) => {
+                headers.push((key.to_lowercase(), value.trim().to_string()));
+            }
+            _ if mode == ParseMode::Strict => {
+                return Err(ParseError::MalformedHeader(line.to_string()));
+            }
+            _ => continue,
+        }
+    }
+
+    Ok(headers)
+}
+
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+// Whether a comma-separated header value lists `token`, ignoring case.
+pub(crate) fn has_token(header: Option<&str>, token: &str) -> bool {
+    header
+        .unwrap_or("")
+        .split(',')
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+fn parse_version(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let digits = value.strip_prefix(['v', 'V']).unwrap_or(value);
+
+    if !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        digits.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn decode_path(raw: &str) -> Result<String, ParseError> {
+    percent_decode(raw, true)
+}
+
+fn decode_query_component(raw: &str) -> Result<String, ParseError> {
+    percent_decode(&raw.replace('+', " "), false)
+}
+
+// Decoded control characters are rejected outright: a `%00` or `%0A` that
+// reaches a file name or a log line can truncate or forge it.
+fn percent_decode(raw: &str, keep_slash: bool) -> Result<String, ParseError> {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                let byte = high << 4 | low;
+                if !(keep_slash && byte == b'/') {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+            }
+        }
+
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    if decoded.iter().any(|byte| byte.is_ascii_control()) {
+        return Err(ParseError::ControlCharacter);
+    }
+
+    Ok(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::parse(raw, ParseMode::Lenient)
+    }
+
+    #[test]
+    fn path_is_decoded_for_routing_and_raw_target_is_kept() {
+        let req = parse("GET /hello%20w%6Frld?q=%41 HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(req.path, "/hello world");
+        assert_eq!(req.raw_path, "/hello%20w%6Frld?q=%41");
+        assert_eq!(req.query.as_deref(), Some("q=%41"));
+    }
+
+    #[test]
+    fn encoded_slash_stays_encoded() {
+        let req = parse("GET /files/a%2Fb%2f..%2Fc HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(req.path, "/files/a%2Fb%2f..%2Fc");
+    }
+
+    #[test]
+    fn malformed_escapes_are_left_alone() {
+        let req = parse("GET /100%25/%zz/%4 HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(req.path, "/100%/%zz/%4");
+    }
+
+    #[test]
+    fn bare_line_feeds_and_folded_lines_are_lenient_only() {
+        let raw = "GET / HTTP/1.1\nHost: x\nX-Long: one\r\n two\r\n\r\n";
+
+        let req = parse(raw).unwrap();
+        assert_eq!(req.get_header("X-Long"), Some("one two"));
+
+        let err = Request::parse(raw, ParseMode::Strict).unwrap_err();
+        assert!(matches!(err, ParseError::BareLineFeed));
+        let err = Request::parse(
+            "GET / HTTP/1.1\r\nHost: x\r\nX: a\r\n b\r\n\r\n",
+            ParseMode::Strict,
+        )
+        .unwrap_err();
+        assert!(matches!(err, ParseError::ObsFold));
+    }
+
+    #[test]
+    fn duplicate_host_is_rejected_in_every_mode() {
+        let raw = "GET / HTTP/1.1\r\nHost: a\r\nhost: b\r\n\r\n";
+
+        for mode in [ParseMode::Lenient, ParseMode::Strict] {
+            assert!(matches!(
+                Request::parse(raw, mode),
+                Err(ParseError::DuplicateHost)
+            ));
+        }
+    }
+
+    #[test]
+    fn decoded_control_characters_are_rejected() {
+        for target in [
+            "/a%00b",
+            "/a%0A",
+            "/a%7f",
+            "/a?q=%00",
+            "/a?x%0d=1",
+            "/a?q=%0a",
+        ] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            assert!(
+                matches!(parse(&raw), Err(ParseError::ControlCharacter)),
+                "{}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn escaped_printable_characters_are_allowed() {
+        let req = parse("GET /a%20b?q=%41%2B HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(req.path, "/a b");
+        assert_eq!(
+            req.query_params(),
+            vec![("q".to_string(), "A+".to_string())]
+        );
+    }
+
+    #[test]
+    fn headerless_requests_parse_unless_strict_needs_a_host() {
+        let req = parse("GET /a HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.path, "/a");
+        assert!(req.headers.is_empty());
+
+        let req = Request::parse("GET /a HTTP/1.0\r\n\r\n", ParseMode::Strict).unwrap();
+        assert!(req.headers.is_empty());
+
+        assert!(matches!(
+            Request::parse("GET /a HTTP/1.1\r\n\r\n", ParseMode::Strict),
+            Err(ParseError::MissingHost)
+        ));
+    }
+
+    #[test]
+    fn leading_line_breaks_are_skipped() {
+        let req = parse("\r\n\nGET /a HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(req.path, "/a");
+    }
+
+    #[test]
+    fn api_version_defaults_to_one() {
+        let req = parse("GET /api HTTP/1.1\r\nAccept: application/json\r\n\r\n").unwrap();
+
+        assert_eq!(req.api_version(), 1);
+    }
+
+    #[test]
+    fn api_version_prefers_the_query_over_accept() {
+        let accept = "Accept: application/json; version=3\r\n";
+
+        let req = parse(&format!("GET /api?v=v2 HTTP/1.1\r\n{}\r\n", accept)).unwrap();
+        assert_eq!(req.api_version(), 2);
+
+        let req = parse(&format!("GET /api?v=next HTTP/1.1\r\n{}\r\n", accept)).unwrap();
+        assert_eq!(req.api_version(), 3);
+    }
+
+    #[test]
+    fn api_version_can_come_from_a_header() {
+        let req = parse("GET /api?v=2 HTTP/1.1\r\nApi-Version: 4\r\n\r\n").unwrap();
+
+        assert_eq!(
+            req.api_version_from(&[VersionSource::Header("api-version")]),
+            4
+        );
+        assert_eq!(
+            req.api_version_from(&[VersionSource::Header("X-Version")]),
+            1
+        );
+    }
+
+    #[test]
+    fn connect_keeps_its_authority_form_target() {
+        let req =
+            parse("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").unwrap();
+
+        assert_eq!(req.verb, Verb::Connect);
+        assert_eq!(req.path, "example.com:443");
+        assert_eq!(req.verb.to_string(), "CONNECT");
+    }
+
+    #[test]
+    fn bodies_are_kept_byte_for_byte() {
+        let mut raw = b"POST /files/a.bin HTTP/1.1\r\nContent-Length: 4\r\n\r\n".to_vec();
+        raw.extend_from_slice(&[0xff, 0x00, 0xc3, 0x28]);
+
+        let req = Request::parse_bytes(&raw, ParseMode::Lenient).unwrap();
+
+        assert_eq!(req.body, [0xff, 0x00, 0xc3, 0x28]);
+    }
+}
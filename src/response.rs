@@ -0,0 +1,385 @@
+use std::fmt::Display;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Receiver;
+
+use crate::{Encoding, HttpError, StatusCode};
+
+// Bodies up to this size go out in one write with the head, and messages a
+// stream producer has already queued are combined into one write of up to
+// this many bytes.
+const COALESCE_LIMIT: usize = 64 * 1024;
+
+pub struct Response {
+    status: StatusCode,
+    // Overrides the status code's canonical reason phrase.
+    status_text: Option<String>,
+    body: Vec<u8>,
+    headers: Vec<(String, String)>,
+    body_stream: Option<Receiver<Vec<u8>>>,
+    // What went wrong behind an error response, only shown in debug mode.
+    detail: Option<String>,
+}
+
+impl Response {
+    pub fn new() -> Response {
+        Self::with_status(StatusCode::OK)
+    }
+
+    pub fn with_status(status: StatusCode) -> Response {
+        Response {
+            status,
+            status_text: None,
+            body: Vec::new(),
+            headers: Vec::new(),
+            body_stream: None,
+            detail: None,
+        }
+    }
+
+    /// An error response with `message` as its plain text body.
+    pub fn error(status: StatusCode, message: &str) -> Response {
+        let mut response = Self::with_status(status);
+        response.set_header("Content-Type", "text/plain");
+        response.set_body(message);
+        response
+    }
+
+    pub fn set_body(&mut self, body: &str) {
+        self.body = body.as_bytes().to_vec();
+    }
+
+    /// Streams the body from `chunks` instead of sending `body()`: messages
+    /// are written out as they arrive, until every sender is dropped, with any
+    /// already waiting combined into one write. The bounded channel holds
+    /// producers back while the client is slow to read. Streamed bodies go out
+    /// with chunked encoding (or up to the close for HTTP/1.0 clients), unless
+    /// a `Content-Length` is set for them, in which case the stream must
+    /// produce exactly that many bytes. A stream that turns out to be short is
+    /// sent like any other body (see [`Server::set_stream_buffer_limit`]).
+    ///
+    /// [`Server::set_stream_buffer_limit`]: crate::Server::set_stream_buffer_limit
+    pub fn set_body_stream(&mut self, chunks: Receiver<Vec<u8>>) {
+        self.body = Vec::new();
+        self.body_stream = Some(chunks);
+    }
+
+    /// Reads a streamed body into `body()` until the stream ends or more than
+    /// `limit` bytes have arrived. A stream that ends in time becomes an
+    /// ordinary body; otherwise what was read is sent ahead of the rest.
+    pub(crate) async fn buffer_body_stream(&mut self, limit: usize) {
+        let Some(chunks) = &mut self.body_stream else {
+            return;
+        };
+
+        while self.body.len() <= limit {
+            match chunks.recv().await {
+                Some(chunk) => self.body.extend_from_slice(&chunk),
+                None => {
+                    self.body_stream = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn has_body_stream(&self) -> bool {
+        self.body_stream.is_some()
+    }
+
+    pub(crate) fn take_body_stream(&mut self) -> Option<Receiver<Vec<u8>>> {
+        self.body_stream.take()
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        self.headers.push((key.to_string(), value.to_string()));
+    }
+
+    pub fn set_body_bytes(&mut self, body: &[u8]) {
+        self.body = body.to_vec();
+    }
+
+    pub fn append_body(&mut self, extra: &[u8]) {
+        self.body.extend_from_slice(extra);
+    }
+
+    pub fn remove_header(&mut self, key: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn status_code(&self) -> u32 {
+        self.status.as_u32()
+    }
+
+    /// The reason phrase set with [`set_status_text`](Self::set_status_text),
+    /// or the status code's canonical one.
+    pub fn status_text(&self) -> &str {
+        self.status_text
+            .as_deref()
+            .unwrap_or_else(|| self.status.reason_phrase())
+    }
+
+    /// Changing the status also resets the reason phrase to the new code's
+    /// canonical one.
+    pub fn set_status(&mut self, status: StatusCode) {
+        self.status = status;
+        self.status_text = None;
+    }
+
+    pub fn set_status_code(&mut self, status_code: u32) {
+        self.set_status(status_code.into());
+    }
+
+    pub fn set_status_text(&mut self, status_text: &str) {
+        self.status_text = Some(status_text.to_string());
+    }
+
+    /// Writes the whole response, however much larger than the socket buffer
+    /// it is: `write_all` keeps writing as the client drains the socket, so a
+    /// slow reader only holds up its own connection's task, for as long as it
+    /// takes. On error part of the response may have been written already.
+    pub async fn send(&mut self, stream: &mut TcpStream) -> std::io::Result<()> {
+        if let Some(chunks) = self.body_stream.take() {
+            return self.send_stream(stream, chunks).await;
+        }
+
+        let mut head = self.head().into_bytes();
+
+        // Small bodies go out in the same write as the head so they can share
+        // a segment; large ones are written from where they are rather than
+        // copied next to it.
+        if self.body.len() <= COALESCE_LIMIT {
+            head.extend_from_slice(&self.body);
+            return stream.write_all(&head).await;
+        }
+
+        stream.write_all(&head).await?;
+        stream.write_all(&self.body).await
+    }
+
+    async fn send_stream(
+        &mut self,
+        stream: &mut TcpStream,
+        mut chunks: Receiver<Vec<u8>>,
+    ) -> std::io::Result<()> {
+        let chunked = self
+            .get_header("Transfer-Encoding")
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+
+        // Anything buffered by `buffer_body_stream` goes out with the head.
+        let mut pending = std::mem::take(&mut self.body);
+        let mut response = self.head().into_bytes();
+        if chunked {
+            response.extend_from_slice(&frame_chunk(&pending));
+        } else {
+            response.extend_from_slice(&pending);
+        }
+        stream.write_all(&response).await?;
+
+        // Whatever the producer has already queued goes out in one write (and
+        // one chunk), so lots of small messages don't each cost a packet.
+        while let Some(chunk) = chunks.recv().await {
+            pending = chunk;
+            while pending.len() < COALESCE_LIMIT {
+                match chunks.try_recv() {
+                    Ok(chunk) => pending.extend_from_slice(&chunk),
+                    Err(_) => break,
+                }
+            }
+
+            if chunked {
+                stream.write_all(&frame_chunk(&pending)).await?;
+            } else {
+                stream.write_all(&pending).await?;
+            }
+        }
+
+        if chunked {
+            stream.write_all(b"0\r\n\r\n").await?;
+        }
+        Ok(())
+    }
+
+    /// Narrows a 200 response down to the byte range asked for by a `Range`
+    /// header, turning it into a 206 (or a 416 if the range starts past the
+    /// end of the body). Responses that already have another status, headers
+    /// that are not a single `bytes=` range, and no header at all leave the
+    /// full body in place. Either way the response advertises
+    /// `Accept-Ranges: bytes`.
+    pub fn apply_range(&mut self, range: Option<&str>) {
+        self.remove_header("Accept-Ranges");
+        self.set_header("Accept-Ranges", "bytes");
+
+        let range = match range {
+            Some(range) if self.status == StatusCode::OK => range,
+            _ => return,
+        };
+
+        let len = self.body.len();
+        match parse_byte_range(range, len) {
+            ByteRange::Satisfiable(start, end) => {
+                self.body = self.body[start..=end].to_vec();
+                self.set_status(StatusCode::PARTIAL_CONTENT);
+                self.set_header("Content-Range", &format!("bytes {}-{}/{}", start, end, len));
+            }
+            ByteRange::Unsatisfiable => {
+                self.body.clear();
+                self.set_status(StatusCode::RANGE_NOT_SATISFIABLE);
+                self.set_header("Content-Range", &format!("bytes */{}", len));
+            }
+            ByteRange::Ignored => {}
+        }
+    }
+
+    fn head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.status_text());
+
+        for (key, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", key, value));
+        }
+
+        head.push_str("\r\n");
+        head
+    }
+
+    /// Compresses the body with `encoding`, labelling it with
+    /// `Content-Encoding` and updating `Content-Length`. A body that already
+    /// has a `Content-Encoding` is left as it is.
+    pub fn compress_with(&mut self, encoding: Encoding) {
+        if self.get_header("Content-Encoding").is_some() {
+            return;
+        }
+
+        self.body = encoding.encode(&self.body);
+        self.set_header("Content-Encoding", &encoding.to_string());
+        self.vary_on_accept_encoding();
+        self.remove_header("Content-Length");
+        self.set_header("Content-Length", &self.body.len().to_string());
+    }
+
+    // Adds `Accept-Encoding` to `Vary`, unless it is already covered.
+    pub(crate) fn vary_on_accept_encoding(&mut self) {
+        match self.get_header("Vary").map(str::to_string) {
+            None => self.set_header("Vary", "Accept-Encoding"),
+            Some(vary)
+                if vary.split(',').any(|field| {
+                    field.trim() == "*" || field.trim().eq_ignore_ascii_case("Accept-Encoding")
+                }) => {}
+            Some(vary) => {
+                self.remove_header("Vary");
+                self.set_header("Vary", &format!("{}, Accept-Encoding", vary));
+            }
+        }
+    }
+
+    pub(crate) fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+
+    pub(crate) fn set_detail(&mut self, detail: String) {
+        self.detail = Some(detail);
+    }
+
+    pub fn permits_body(&self) -> bool {
+        self.status.permits_body()
+    }
+
+    pub fn no_content() -> Self {
+        Self::with_status(StatusCode::NO_CONTENT)
+    }
+
+    pub fn new_400() -> Self {
+        let mut response = Self::with_status(StatusCode::BAD_REQUEST);
+        response.set_body("Bad Request");
+        response
+    }
+
+    pub fn new_404() -> Self {
+        let mut response = Self::with_status(StatusCode::NOT_FOUND);
+        response.set_body("Not Found");
+        response
+    }
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A 500 whose body is just the status text; the error itself is logged and,
+/// in debug mode, appended to the body.
+impl From<anyhow::Error> for Response {
+    fn from(err: anyhow::Error) -> Self {
+        HttpError::Internal(err).into()
+    }
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.head(), String::from_utf8_lossy(&self.body))
+    }
+}
+
+// One chunk of a chunked body; nothing for no data, as an empty chunk would
+// end the body.
+fn frame_chunk(data: &[u8]) -> Vec<u8> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+
+    let mut framed = format!("{:x}\r\n", data.len()).into_bytes();
+    framed.extend_from_slice(data);
+    framed.extend_from_slice(b"\r\n");
+    framed
+}
+
+enum ByteRange {
+    Satisfiable(usize, usize),
+    Unsatisfiable,
+    Ignored,
+}
+
+// Only single ranges are supported: `bytes=start-end`, `bytes=start-` and the
+// suffix form `bytes=-length`. Anything else is ignored, which RFC 9110 allows.
+fn parse_byte_range(range: &str, len: usize) -> ByteRange {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Ignored,
+    };
+
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Ignored,
+    };
+
+    let (start, end) = match (start.parse::<usize>(), end.parse::<usize>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return ByteRange::Ignored,
+    };
+
+    if start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Satisfiable(start, end)
+    }
+}
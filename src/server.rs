@@ -0,0 +1,3274 @@
+use anyhow::Context;
+use anyhow::Result;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Semaphore};
+
+use crate::access::AccessList;
+use crate::auth::Authenticator;
+use crate::body::SpilledBody;
+use crate::finalize::{finalize, FinalizeConfig};
+use crate::inspect::{inspect, INSPECT_PATH};
+use crate::pattern::PathPattern;
+use crate::request::{has_token, head_end};
+use crate::websocket::{self, WebSocketHandler};
+use crate::{
+    CompressibleTypes, HttpError, IpRange, ParseError, ParseMode, Request, Response, StatusCode,
+    Verb,
+};
+
+const ACCEPT_BACKOFF_START: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+const DEFAULT_STREAM_BUFFER_LIMIT: usize = 4 * 1024;
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+const DEFAULT_SPILL_THRESHOLD: usize = 1024 * 1024;
+// The request line and headers together, and separately a chunked body's
+// trailers together with the head.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+// A chunk size line, extensions included.
+const MAX_CHUNK_LINE: usize = 1024;
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Route {
+    path: String,
+    pattern: PathPattern,
+    verb: Verb,
+    content_type: Option<String>,
+    upload: bool,
+    authenticator: Option<Box<dyn Authenticator>>,
+    auto_methods: bool,
+}
+
+impl Route {
+    /// `path` is matched segment by segment: a `:name` segment matches any
+    /// one segment and a final `*name` segment matches the rest of the path,
+    /// either way capturing what it matched as the request's
+    /// [`param`](Request::param) `name`. Other segments must match exactly.
+    ///
+    /// # Panics
+    ///
+    /// If a `*name` segment is not the last one.
+    pub fn new(path: &str, verb: Verb) -> Self {
+        Self {
+            path: path.to_string(),
+            pattern: PathPattern::parse(path),
+            verb,
+            content_type: None,
+            upload: false,
+            authenticator: None,
+            auto_methods: true,
+        }
+    }
+
+    pub fn with_content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    /// Marks the route as writing request bodies to disk, so it counts
+    /// towards the server's concurrent upload limit.
+    pub fn as_upload(mut self) -> Self {
+        self.upload = true;
+        self
+    }
+
+    /// Checks requests to this route with `authenticator` instead of the
+    /// server-wide one.
+    pub fn with_authenticator(mut self, authenticator: Box<dyn Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Leaves this route out of the automatic HEAD and OPTIONS handling, for
+    /// routes that register their own HEAD or OPTIONS handlers or should not
+    /// be advertised.
+    pub fn without_auto_methods(mut self) -> Self {
+        self.auto_methods = false;
+        self
+    }
+
+    fn apply_defaults(&self, response: &mut Response) {
+        if let Some(content_type) = &self.content_type {
+            if response.get_header("Content-Type").is_none() {
+                response.set_header("Content-Type", content_type);
+            }
+        }
+    }
+
+    fn does_match(&self, verb: &Verb, path: &str) -> bool {
+        &self.verb == verb && self.matches_path(path)
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        self.pattern.matches(path).is_some()
+    }
+}
+
+// A route chosen for a request, with the parameters its path captured.
+type RouteMatch<'a> = (&'a (Route, RouteHandler), Vec<(String, String)>);
+
+/// Handlers are synchronous and run on the connection's task, so anything
+/// blocking they do, such as file I/O, holds up a runtime worker thread until
+/// it returns. Use an [`AsyncHandler`] for those.
+pub type Handler = Box<dyn Fn(&Request) -> Result<Response, HttpError> + Send + Sync>;
+
+/// A handler that can wait, with `tokio::fs` for example, without holding up
+/// the thread its connection runs on. Register it with
+/// [`Server::register_async_route`].
+pub type AsyncHandler = Box<
+    dyn for<'a> Fn(
+            &'a Request,
+        )
+            -> Pin<Box<dyn Future<Output = Result<Response, HttpError>> + Send + 'a>>
+        + Send
+        + Sync,
+>;
+
+enum RouteHandler {
+    Sync(Handler),
+    Async(AsyncHandler),
+}
+
+/// Runs on every request before routing. A filter can rewrite the request in
+/// place (paths, headers) for the filters and routes after it, or return a
+/// response to answer the request without routing it at all.
+pub trait RequestFilter: Send + Sync {
+    fn filter(&self, req: &mut Request) -> Option<Response>;
+}
+
+impl<F> RequestFilter for F
+where
+    F: Fn(&mut Request) -> Option<Response> + Send + Sync,
+{
+    fn filter(&self, req: &mut Request) -> Option<Response> {
+        self(req)
+    }
+}
+
+/// What [`Server::register_route`] does with a route for the same verb and
+/// path as one registered before it. Paths that differ only in what their
+/// parameters are called, like `/users/:id` and `/users/:name`, count as the
+/// same.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateRoutes {
+    /// Refuse the new route with a [`DuplicateRoute`] error.
+    Error,
+    /// Log a warning and keep both; the first one registered handles requests.
+    #[default]
+    Warn,
+    /// Replace the earlier route with the new one.
+    LastWins,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("a route for {verb} {path} is already registered")]
+pub struct DuplicateRoute {
+    pub verb: String,
+    pub path: String,
+}
+
+/// What the built-in `/favicon.ico` handler answers with.
+pub enum Favicon {
+    /// An empty `204 No Content`, which stops browsers asking again.
+    NoContent,
+    /// The contents of an icon file.
+    File(PathBuf),
+}
+
+pub struct Server {
+    // Taken by `listen_until`, which closes it when told to shut down.
+    tcp_listener: Mutex<Option<TcpListener>>,
+    // Set by `listen_until` once it stops accepting, so that connections end
+    // rather than wait for more requests.
+    shutdown: watch::Sender<bool>,
+    local_addr: SocketAddr,
+    root_handler: Option<Handler>,
+    routes: Vec<(Route, RouteHandler)>,
+    filters: Vec<Box<dyn RequestFilter>>,
+    websockets: Vec<(String, WebSocketHandler)>,
+    linger: Option<Duration>,
+    dual_stack: bool,
+    parse_mode: ParseMode,
+    uploads: Option<Semaphore>,
+    stream_buffer_limit: usize,
+    debug: bool,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    log_idle_closures: bool,
+    authenticator: Option<Box<dyn Authenticator>>,
+    favicon: Option<Favicon>,
+    log_favicon: bool,
+    finalize: FinalizeConfig,
+    auto_head: bool,
+    auto_options: bool,
+    max_body_size: usize,
+    spill_threshold: Option<usize>,
+    access: AccessList,
+    duplicate_routes: DuplicateRoutes,
+}
+
+impl Server {
+    pub async fn new(addr: &str) -> Result<Self> {
+        let tcp_listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("problem listening on {}", addr))?;
+        let local_addr = tcp_listener.local_addr()?;
+        let routes = Vec::new();
+
+        Ok(Self {
+            tcp_listener: Mutex::new(Some(tcp_listener)),
+            shutdown: watch::channel(false).0,
+            local_addr,
+            root_handler: None,
+            routes,
+            filters: Vec::new(),
+            websockets: Vec::new(),
+            linger: None,
+            dual_stack: false,
+            parse_mode: ParseMode::default(),
+            uploads: None,
+            stream_buffer_limit: DEFAULT_STREAM_BUFFER_LIMIT,
+            debug: false,
+            idle_timeout: None,
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            log_idle_closures: false,
+            authenticator: None,
+            favicon: None,
+            finalize: FinalizeConfig::default(),
+            auto_head: true,
+            auto_options: true,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            spill_threshold: Some(DEFAULT_SPILL_THRESHOLD),
+            log_favicon: true,
+            access: AccessList::default(),
+            duplicate_routes: DuplicateRoutes::default(),
+        })
+    }
+
+    /// The address the server is listening on, e.g. to find the port picked
+    /// for a `:0` address.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Only fails under [`DuplicateRoutes::Error`], for a route with the same
+    /// verb and path as an earlier one.
+    pub fn register_route(&mut self, route: Route, handler: Handler) -> Result<(), DuplicateRoute> {
+        self.add_route(route, RouteHandler::Sync(handler))
+    }
+
+    /// Like [`register_route`](Self::register_route), for a handler that is
+    /// awaited.
+    pub fn register_async_route(
+        &mut self,
+        route: Route,
+        handler: AsyncHandler,
+    ) -> Result<(), DuplicateRoute> {
+        self.add_route(route, RouteHandler::Async(handler))
+    }
+
+    fn add_route(&mut self, route: Route, handler: RouteHandler) -> Result<(), DuplicateRoute> {
+        let existing = self.routes.iter().position(|(other, _)| {
+            other.verb == route.verb && other.pattern.is_equivalent(&route.pattern)
+        });
+
+        match (existing, self.duplicate_routes) {
+            (None, _) => self.routes.push((route, handler)),
+            (Some(_), DuplicateRoutes::Error) => {
+                return Err(DuplicateRoute {
+                    verb: route.verb.to_string(),
+                    path: route.path,
+                });
+            }
+            (Some(_), DuplicateRoutes::Warn) => {
+                eprintln!(
+                    "Route {} {} is already registered, the first one will be used",
+                    route.verb, route.path
+                );
+                self.routes.push((route, handler));
+            }
+            (Some(index), DuplicateRoutes::LastWins) => self.routes[index] = (route, handler),
+        }
+
+        Ok(())
+    }
+
+    /// Applies to routes registered after it is set.
+    pub fn set_duplicate_routes(&mut self, policy: DuplicateRoutes) {
+        self.duplicate_routes = policy;
+    }
+
+    /// Filters run in the order they were registered.
+    pub fn register_filter(&mut self, filter: Box<dyn RequestFilter>) {
+        self.filters.push(filter);
+    }
+
+    /// Accepts WebSocket upgrades on exactly `path`. Once the handshake has
+    /// been answered with `101 Switching Protocols` the raw stream is handed to
+    /// `handler`, which owns the connection (and its framing) from then on.
+    /// The upgrade request is authenticated first, by the authenticator of a
+    /// GET route on the same path if there is one, else the server's.
+    pub fn websocket<F, Fut>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(TcpStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.websockets.push((
+            path.to_string(),
+            Box::new(move |stream| Box::pin(handler(stream))),
+        ));
+    }
+
+    /// When set, connections are closed by shutting down our write half and
+    /// draining the client for up to `linger` instead of being dropped
+    /// straight after the response is written.
+    pub fn set_graceful_close(&mut self, linger: Option<Duration>) {
+        self.linger = linger;
+    }
+
+    /// An IPv6 listener only serves IPv6 clients unless dual stack is enabled,
+    /// in which case IPv4 clients arriving as IPv4-mapped addresses
+    /// (`::ffff:a.b.c.d`) are served as well. Has no effect on IPv4 listeners.
+    ///
+    /// The socket itself is left as the OS makes it, so this is a filter on
+    /// accepted connections rather than a socket option: with dual stack off,
+    /// IPv4 clients still complete the TCP handshake and are then closed
+    /// without a response, and on a host that makes IPv6 sockets IPv6-only
+    /// (`net.ipv6.bindv6only = 1` on Linux) turning it on has no effect.
+    pub fn set_dual_stack(&mut self, dual_stack: bool) {
+        self.dual_stack = dual_stack;
+    }
+
+    /// See [`ParseMode`] for what each mode accepts.
+    pub fn set_parse_mode(&mut self, parse_mode: ParseMode) {
+        self.parse_mode = parse_mode;
+    }
+
+    /// Caps how many upload routes (see [`Route::as_upload`]) may run at once.
+    /// Uploads over the limit are turned away with 503 and `Retry-After`
+    /// rather than queued, so other traffic is unaffected.
+    pub fn set_max_concurrent_uploads(&mut self, limit: Option<usize>) {
+        self.uploads = limit.map(Semaphore::new);
+    }
+
+    /// Streamed bodies (see [`Response::set_body_stream`]) are read up to this
+    /// many bytes (4 KiB by default) before anything is sent. A stream that
+    /// ends by then is sent in one piece with a `Content-Length` instead of
+    /// chunked. The catch is that a stream is held back until it ends or
+    /// passes the limit, so set 0 for streams that trickle out small messages
+    /// the client should see straight away.
+    pub fn set_stream_buffer_limit(&mut self, limit: usize) {
+        self.stream_buffer_limit = limit;
+    }
+
+    /// In debug mode error responses also say which request they answer, the
+    /// same way the error log does, along with the cause of internal errors,
+    /// and `/__echo` answers any method with the parsed request as JSON, once
+    /// the server's authenticator lets it through. Credential headers are
+    /// redacted from the echo.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// Closes connections that have not started sending a request within
+    /// `idle_timeout`. The close is a plain FIN with no response written.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Once a request has started, answers it with 408 if the client then
+    /// sends nothing for `read_timeout` (30 seconds by default), e.g. because
+    /// it declared a longer body than it sent.
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
+    pub fn set_log_idle_closures(&mut self, log_idle_closures: bool) {
+        self.log_idle_closures = log_idle_closures;
+    }
+
+    /// Checks every request before it reaches a handler, unless the matching
+    /// route has its own authenticator.
+    pub fn set_authenticator(&mut self, authenticator: Option<Box<dyn Authenticator>>) {
+        self.authenticator = authenticator;
+    }
+
+    /// Answers `GET /favicon.ico` without going through the routes. Off by
+    /// default, so the request 404s like any other unknown path. An icon file
+    /// that can't be found is warned about here and answered with
+    /// [`Favicon::NoContent`] instead, as it is if it goes missing later.
+    pub fn set_favicon(&mut self, favicon: Option<Favicon>) {
+        if let Some(Favicon::File(path)) = &favicon {
+            if !path.is_file() {
+                eprintln!(
+                    "Favicon {} not found, answering /favicon.ico with 204",
+                    path.display()
+                );
+                self.favicon = Some(Favicon::NoContent);
+                return;
+            }
+        }
+        self.favicon = favicon;
+    }
+
+    /// Browsers ask for `/favicon.ico` on their own, so with this off its
+    /// error responses (say a 404 with no favicon set) are kept out of the
+    /// error log. On by default.
+    pub fn set_log_favicon(&mut self, log_favicon: bool) {
+        self.log_favicon = log_favicon;
+    }
+
+    /// Once any range is allowed, connections from outside every allowed range
+    /// are closed as soon as they are accepted.
+    pub fn add_allowed_ips(&mut self, range: IpRange) {
+        self.access.allowed.push(range);
+    }
+
+    /// Connections from a denied range are closed as soon as they are
+    /// accepted, even if the range is also allowed.
+    pub fn add_denied_ips(&mut self, range: IpRange) {
+        self.access.denied.push(range);
+    }
+
+    /// Adds a header to every response that does not set it itself.
+    pub fn add_default_header(&mut self, key: &str, value: &str) {
+        self.finalize
+            .default_headers
+            .push((key.to_string(), value.to_string()));
+    }
+
+    pub fn set_server_header(&mut self, server: Option<&str>) {
+        self.finalize.server_header = server.map(str::to_string);
+    }
+
+    /// Appends `; charset=<charset>` to `text/*` content types that do not
+    /// name one.
+    pub fn set_default_charset(&mut self, charset: Option<&str>) {
+        self.finalize.charset = charset.map(str::to_string);
+    }
+
+    /// When on (the default), response bodies are compressed with gzip or
+    /// deflate for requests whose `Accept-Encoding` accepts either.
+    pub fn set_compression(&mut self, compression: bool) {
+        self.finalize.compression = compression;
+    }
+
+    /// Which content types are compressed for clients that accept it: by default
+    /// text and the JSON, JavaScript and XML formats, leaving images, archives
+    /// and other media that are already compressed alone.
+    pub fn set_compressible_types(&mut self, types: CompressibleTypes) {
+        self.finalize.compressible_types = types;
+    }
+
+    /// Bodies shorter than `min_size` bytes are never compressed, since the
+    /// coding's own overhead outweighs what it saves on them.
+    pub fn set_compress_min_size(&mut self, min_size: usize) {
+        self.finalize.compress_min_size = min_size;
+    }
+
+    /// When on (the default), a HEAD request with no HEAD route of its own is
+    /// answered by the matching GET route with the body left off.
+    pub fn set_auto_head(&mut self, auto_head: bool) {
+        self.auto_head = auto_head;
+    }
+
+    /// When on (the default), an OPTIONS request with no OPTIONS route of its
+    /// own is answered with 204 and an `Allow` header listing the methods
+    /// routed for that path.
+    pub fn set_auto_options(&mut self, auto_options: bool) {
+        self.auto_options = auto_options;
+    }
+
+    /// Requests declaring a larger body than this are answered with 413
+    /// without reading the body. The head, and separately the head together
+    /// with a chunked body's trailers, have a fixed cap of 16 KiB, past which
+    /// the request is answered with 431.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// Request bodies larger than this many bytes are written to a file in
+    /// the system temp directory as they arrive instead of being held in
+    /// memory; handlers read them with [`Request::body_reader`] or move them
+    /// into place with [`Request::save_body`]. `None` keeps every body in
+    /// memory. Defaults to 1 MiB.
+    pub fn set_spill_threshold(&mut self, threshold: Option<usize>) {
+        self.spill_threshold = threshold;
+    }
+
+    pub fn set_root_handler(&mut self, handler: Handler) {
+        self.root_handler = Some(handler);
+    }
+
+    pub async fn listen(self: Arc<Self>) -> Result<()> {
+        self.listen_until(std::future::pending()).await
+    }
+
+    /// Like [`listen`](Self::listen), until `shutdown` completes. The listening
+    /// socket is then closed, so new connections are refused, and this returns
+    /// once the connections already accepted have been answered. A connection
+    /// kept open between requests is closed right away, and one in the middle
+    /// of a request is closed after its response.
+    pub async fn listen_until(self: Arc<Self>, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let tcp_listener = self
+            .tcp_listener
+            .lock()
+            .unwrap()
+            .take()
+            .context("the server is already listening")?;
+        // Every connection's task holds a sender, so `recv` on the receiver
+        // only returns once the last of them has finished.
+        let (in_flight, mut finished) = tokio::sync::mpsc::channel::<()>(1);
+        let mut backoff = ACCEPT_BACKOFF_START;
+        tokio::pin!(shutdown);
+
+        loop {
+            // Connections that were already waiting are accepted first, so
+            // they are answered rather than reset.
+            let accepted = tokio::select! {
+                biased;
+                accepted = tcp_listener.accept() => accepted,
+                () = &mut shutdown => break,
+            };
+
+            let (stream, peer) = match accepted {
+                Ok(connection) => {
+                    backoff = ACCEPT_BACKOFF_START;
+                    connection
+                }
+                Err(err) if is_connection_error(&err) => continue,
+                Err(err) if is_fatal_accept_error(&err) => {
+                    return Err(err).context("Error accepting");
+                }
+                Err(err) => {
+                    eprintln!("Error accepting, retrying in {:?}: {}", backoff, err);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                    continue;
+                }
+            };
+
+            if !self.dual_stack && is_ipv4_mapped(&peer) {
+                eprintln!(
+                    "Refusing IPv4 connection from {} without dual stack",
+                    peer.ip()
+                );
+                continue;
+            }
+
+            if !self.access.permits(peer.ip()) {
+                eprintln!("Refusing connection from {}", peer.ip());
+                continue;
+            }
+
+            tokio::spawn({
+                let me = Arc::clone(&self);
+                let in_flight = in_flight.clone();
+                async move {
+                    let _ = me.handle_connection(stream).await;
+                    drop(in_flight);
+                }
+            });
+        }
+
+        self.shutdown.send_replace(true);
+        drop(tcp_listener);
+        drop(in_flight);
+        let _ = finished.recv().await;
+        Ok(())
+    }
+
+    // Answers requests on the connection one after another until one of them
+    // is the last (see `finish`), the client goes away or goes idle, or the
+    // connection is handed to a WebSocket handler.
+    async fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        let mut reader = RequestReader::new(
+            self.max_body_size,
+            self.spill_threshold,
+            self.read_timeout,
+            self.shutdown.subscribe(),
+        );
+
+        loop {
+            match self.handle_request(&mut stream, &mut reader).await? {
+                Outcome::KeepAlive => continue,
+                Outcome::Close => break,
+                Outcome::Closed => return Ok(()),
+                Outcome::Upgrade(handler) => {
+                    handler(stream).await;
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Some(linger) = self.linger {
+            close_gracefully(stream, linger).await;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_request(
+        &self,
+        stream: &mut TcpStream,
+        reader: &mut RequestReader,
+    ) -> Result<Outcome<'_>> {
+        let request_bytes = match reader.next_request(stream, self.idle_timeout).await {
+            Ok(Some(request_bytes)) => request_bytes,
+            Ok(None) => return Ok(Outcome::Closed),
+            Err(ReadError::Idle) => {
+                // Nothing is pending, so there is nothing to answer: just send
+                // our FIN so the client sees a clean close rather than a reset.
+                if self.log_idle_closures {
+                    eprintln!("Closing idle connection");
+                }
+                let _ = stream.shutdown().await;
+                return Ok(Outcome::Closed);
+            }
+            Err(ReadError::ShuttingDown) => {
+                let _ = stream.shutdown().await;
+                return Ok(Outcome::Closed);
+            }
+            Err(
+                err @ (ReadError::TruncatedHead
+                | ReadError::TruncatedBody { .. }
+                | ReadError::TruncatedChunkedBody
+                | ReadError::InvalidContentLength
+                | ReadError::InvalidTransferEncoding(_)
+                | ReadError::MalformedChunk
+                | ReadError::ChunkLineTooLong { .. }),
+            ) => {
+                let context = err.to_string();
+                return self
+                    .finish(stream, Response::new_400(), None, Some(&context))
+                    .await;
+            }
+            Err(err @ ReadError::Timeout) => {
+                let context = err.to_string();
+                let status = StatusCode::REQUEST_TIMEOUT;
+                let response = Response::error(status, status.reason_phrase());
+                return self.finish(stream, response, None, Some(&context)).await;
+            }
+            Err(err @ ReadError::BodyTooLarge { .. }) => {
+                let context = err.to_string();
+                let response = HttpError::PayloadTooLarge.into();
+                return self.finish(stream, response, None, Some(&context)).await;
+            }
+            Err(err @ ReadError::UnsupportedTransferCoding(_)) => {
+                let context = err.to_string();
+                let response = HttpError::NotImplemented.into();
+                return self.finish(stream, response, None, Some(&context)).await;
+            }
+            Err(err @ ReadError::HeadTooLarge { .. }) => {
+                let context = err.to_string();
+                let status = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+                let response = Response::error(status, status.reason_phrase());
+                return self.finish(stream, response, None, Some(&context)).await;
+            }
+            Err(ReadError::Spill(err)) => {
+                let context = format!("request body could not be spilled to disk: {}", err);
+                let response = HttpError::Internal(err.into()).into();
+                return self.finish(stream, response, None, Some(&context)).await;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let mut req = match Request::parse_bytes(&request_bytes, self.parse_mode) {
+            Ok(mut req) => {
+                if let Some(body) = reader.take_spilled_body() {
+                    req.set_spilled_body(body);
+                }
+                req
+            }
+            Err(err) => {
+                let request = String::from_utf8_lossy(&request_bytes);
+                let context = format!("{:?}", request.lines().next().unwrap_or(""));
+                let response = match err {
+                    ParseError::UnknownVerb(_) => HttpError::NotImplemented.into(),
+                    err => HttpError::BadRequest(err.to_string()).into(),
+                };
+                return self.finish(stream, response, None, Some(&context)).await;
+            }
+        };
+
+        for filter in &self.filters {
+            if let Some(response) = filter.filter(&mut req) {
+                let context = describe(&req);
+                return self
+                    .finish(stream, response, Some(&req), Some(&context))
+                    .await;
+            }
+        }
+
+        if websocket::is_upgrade_request(&req) {
+            if let Some((_, handler)) = self.websockets.iter().find(|(path, _)| path == &req.path) {
+                // A GET route on the same path lends its authenticator.
+                let route = self.find_route(&req).map(|((route, _), _)| route);
+                if let Some(response) = self.authenticate(route, &req) {
+                    return self
+                        .finish(stream, response, Some(&req), Some(&describe(&req)))
+                        .await;
+                }
+
+                match websocket::handshake(&req) {
+                    Ok(mut response) => {
+                        finalize(&mut response, Some(&req), &self.finalize);
+                        response.send(stream).await?;
+                        return Ok(Outcome::Upgrade(handler));
+                    }
+                    Err(response) => {
+                        return self
+                            .finish(stream, response, Some(&req), Some(&describe(&req)))
+                            .await
+                    }
+                }
+            }
+        }
+
+        let response = self.route(&mut req).await;
+        // Browsers ask for the icon on their own; its misses needn't be logged.
+        if !self.log_favicon && req.path == "/favicon.ico" {
+            return self.finish(stream, response, Some(&req), None).await;
+        }
+        self.finish(stream, response, Some(&req), Some(&describe(&req)))
+            .await
+    }
+
+    async fn route(&self, req: &mut Request) -> Response {
+        if let Some(favicon) = &self.favicon {
+            if req.verb == Verb::Get && req.path == "/favicon.ico" {
+                return serve_favicon(favicon);
+            }
+        }
+
+        // There is no tunnelling support, so CONNECT is refused outright
+        // unless a route has been registered for it.
+        if req.verb == Verb::Connect && self.find_route(req).is_none() {
+            return Response::error(
+                StatusCode::NOT_IMPLEMENTED,
+                "CONNECT tunnels are not supported",
+            );
+        }
+
+        // The echo endpoint sits behind the server's authenticator like any
+        // other path, so debug mode doesn't open a way around it.
+        if self.debug && req.path == INSPECT_PATH {
+            if let Some(response) = self.authenticate(None, req) {
+                return response;
+            }
+            return inspect(req);
+        }
+
+        if req.verb == Verb::Options && self.auto_options {
+            if let Some(response) = self.options(req) {
+                return response;
+            }
+        }
+
+        if req.path == "/" {
+            if let Some(response) = self.authenticate(None, req) {
+                return response;
+            }
+
+            return match &self.root_handler {
+                Some(root_handler) => root_handler(req).unwrap_or_else(Response::from),
+                None => HttpError::NotFound.into(),
+            };
+        }
+
+        if let Some(((route, handler), params)) = self.find_route(req) {
+            req.params = params;
+            let req = &*req;
+
+            if let Some(response) = self.authenticate(Some(route), req) {
+                return response;
+            }
+
+            let _permit = match &self.uploads {
+                Some(uploads) if route.upload => match uploads.try_acquire() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => return HttpError::ServiceUnavailable { retry_after: 1 }.into(),
+                },
+                _ => None,
+            };
+
+            let result = match handler {
+                RouteHandler::Sync(handler) => handler(req),
+                RouteHandler::Async(handler) => handler(req).await,
+            };
+            match result {
+                Ok(mut response) => {
+                    route.apply_defaults(&mut response);
+                    response
+                }
+                Err(err) => err.into(),
+            }
+        } else if let Some(allowed) = self.allowed_verbs(&req.path) {
+            let mut response: Response = HttpError::MethodNotAllowed.into();
+            response.set_header("Allow", &allowed);
+            response
+        } else {
+            HttpError::NotFound.into()
+        }
+    }
+
+    fn find_route(&self, req: &Request) -> Option<RouteMatch<'_>> {
+        let found = self.best_route(&req.path, |route| route.verb == req.verb);
+
+        if found.is_some() || req.verb != Verb::Head || !self.auto_head {
+            return found;
+        }
+
+        self.best_route(&req.path, |route| {
+            route.auto_methods && route.verb == Verb::Get
+        })
+    }
+
+    // The most specific of the routes matching `path` that `accept` lets
+    // through. Routes that are as specific as each other go to whichever was
+    // registered first.
+    fn best_route(&self, path: &str, accept: impl Fn(&Route) -> bool) -> Option<RouteMatch<'_>> {
+        let mut best: Option<RouteMatch<'_>> = None;
+
+        for entry in &self.routes {
+            let route = &entry.0;
+            if !accept(route) {
+                continue;
+            }
+            let Some(params) = route.pattern.matches(path) else {
+                continue;
+            };
+
+            let more_specific = match &best {
+                Some(((current, _), _)) => {
+                    route.pattern.specificity() > current.pattern.specificity()
+                }
+                None => true,
+            };
+            if more_specific {
+                best = Some((entry, params));
+            }
+        }
+
+        best
+    }
+
+    // The automatic answer to an OPTIONS request, unless a route handles
+    // OPTIONS for this path itself.
+    fn options(&self, req: &Request) -> Option<Response> {
+        if self
+            .routes
+            .iter()
+            .any(|(route, _)| route.does_match(&Verb::Options, &req.path))
+        {
+            return None;
+        }
+
+        let Some(allowed) = self.allowed_verbs(&req.path) else {
+            return Some(HttpError::NotFound.into());
+        };
+
+        let mut response = Response::no_content();
+        response.set_header("Allow", &allowed);
+        Some(response)
+    }
+
+    // The `Allow` header for a path, listing the methods of the advertised
+    // routes that match it, or `None` if there are none.
+    fn allowed_verbs(&self, path: &str) -> Option<String> {
+        let mut allowed: Vec<&Verb> = self
+            .routes
+            .iter()
+            .filter(|(route, _)| route.auto_methods && route.matches_path(path))
+            .map(|(route, _)| &route.verb)
+            .collect();
+
+        if path == "/" && self.root_handler.is_some() {
+            allowed.push(&Verb::Get);
+        }
+
+        if allowed.is_empty() {
+            return None;
+        }
+
+        if self.auto_head && allowed.contains(&&Verb::Get) {
+            allowed.push(&Verb::Head);
+        }
+        if self.auto_options {
+            allowed.push(&Verb::Options);
+        }
+        allowed.sort();
+        allowed.dedup();
+
+        let allowed = allowed
+            .iter()
+            .map(|verb| verb.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some(allowed)
+    }
+
+    // Returns the response to send instead of running the handler, if the
+    // request is not allowed through.
+    fn authenticate(&self, route: Option<&Route>, req: &Request) -> Option<Response> {
+        let authenticator = route
+            .and_then(|route| route.authenticator.as_ref())
+            .or(self.authenticator.as_ref())?;
+
+        authenticator.authenticate(req).into_response()
+    }
+
+    // `req` is the request being answered, if it could be parsed. `context`
+    // says which request the response answers, for the error log; without it
+    // the response isn't logged.
+    async fn finish(
+        &self,
+        stream: &mut TcpStream,
+        mut response: Response,
+        req: Option<&Request>,
+        context: Option<&str>,
+    ) -> Result<Outcome<'_>> {
+        if self.stream_buffer_limit > 0 {
+            response.buffer_body_stream(self.stream_buffer_limit).await;
+        }
+
+        if let Some(context) = context.filter(|_| response.status().is_error()) {
+            eprintln!(
+                "{} {} for {}",
+                response.status(),
+                response.status_text(),
+                context
+            );
+
+            if self.debug && response.permits_body() {
+                response.append_body(format!("\n\n{}", context).as_bytes());
+                if let Some(detail) = response.detail().map(str::to_string) {
+                    response.append_body(format!("\n{}", detail).as_bytes());
+                }
+            }
+        }
+
+        // Once the server is shutting down, the response being sent is the
+        // connection's last.
+        if *self.shutdown.borrow() {
+            response.remove_header("Connection");
+            response.set_header("Connection", "close");
+        }
+
+        finalize(&mut response, req, &self.finalize);
+        // Part of the response may already be on the wire when a write fails,
+        // so the stream is in an unknown state: drop it right away instead of
+        // lingering on it or reading anything else from it.
+        if let Err(err) = response.send(stream).await {
+            if let Some(context) = context {
+                eprintln!(
+                    "Dropping connection after failed write for {}: {}",
+                    context, err
+                );
+            }
+            return Err(err.into());
+        }
+
+        // finalize has marked the response if the connection ends with it.
+        if has_token(response.get_header("Connection"), "close") {
+            Ok(Outcome::Close)
+        } else {
+            Ok(Outcome::KeepAlive)
+        }
+    }
+}
+
+// What happens to a connection after a request.
+enum Outcome<'a> {
+    KeepAlive,
+    /// The response was the last one; close the connection.
+    Close,
+    /// The client has gone or was dropped for idling; nothing left to do.
+    Closed,
+    Upgrade(&'a WebSocketHandler),
+}
+
+fn serve_favicon(favicon: &Favicon) -> Response {
+    let path = match favicon {
+        Favicon::NoContent => return Response::no_content(),
+        Favicon::File(path) => path,
+    };
+
+    // Already warned about at startup if it was never there.
+    let Ok(icon) = std::fs::read(path) else {
+        return Response::no_content();
+    };
+    let content_type = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("png") => "image/png",
+        Some("svg") => "image/svg+xml",
+        Some("gif") => "image/gif",
+        _ => "image/x-icon",
+    };
+
+    let mut response = Response::new();
+    response.set_header("Content-Type", content_type);
+    response.set_body_bytes(&icon);
+    response
+}
+
+fn describe(req: &Request) -> String {
+    format!("{} {}", req.verb, req.raw_path)
+}
+
+// Sends our FIN and then reads (and throws away) whatever the client still
+// has in flight until it closes its side or `linger` runs out. Dropping a
+// socket with unread data makes the kernel send an RST, which can destroy the
+// tail of a response the client has not read yet.
+async fn close_gracefully(mut stream: TcpStream, linger: Duration) {
+    if stream.shutdown().await.is_err() {
+        return;
+    }
+
+    let mut buf = [0; 1024];
+    let _ = tokio::time::timeout(linger, async {
+        while let Ok(bytes_read) = stream.read(&mut buf).await {
+            if bytes_read == 0 {
+                break;
+            }
+        }
+    })
+    .await;
+}
+
+#[derive(Debug, thiserror::Error)]
+enum ReadError {
+    #[error("client closed the connection in the middle of the request head")]
+    TruncatedHead,
+    #[error("client closed the connection after {received} of {declared} body bytes")]
+    TruncatedBody { declared: usize, received: usize },
+    #[error("client closed the connection in the middle of a chunked body")]
+    TruncatedChunkedBody,
+    #[error("invalid Content-Length")]
+    InvalidContentLength,
+    #[error("invalid Transfer-Encoding: {0}")]
+    InvalidTransferEncoding(&'static str),
+    #[error("unsupported transfer coding {0:?}")]
+    UnsupportedTransferCoding(String),
+    #[error("malformed chunk")]
+    MalformedChunk,
+    #[error("chunk size line is longer than {limit} bytes")]
+    ChunkLineTooLong { limit: usize },
+    #[error("declared body is larger than the {limit} byte limit")]
+    BodyTooLarge { limit: usize },
+    #[error("request head and trailers are larger than the {limit} byte limit")]
+    HeadTooLarge { limit: usize },
+    #[error("problem writing the request body to a temp file")]
+    Spill(#[source] std::io::Error),
+    #[error("no request arrived within the idle timeout")]
+    Idle,
+    #[error("the server shut down between requests")]
+    ShuttingDown,
+    #[error("client stopped sending in the middle of a request")]
+    Timeout,
+    #[error("problem reading into buffer")]
+    Io(#[from] std::io::Error),
+}
+
+// Reads requests off a connection one at a time. Each call reads the head of
+// the next request and then its whole body, framed by Content-Length or
+// chunked, so the body is always consumed off the socket whether or not the
+// handler looks at it. A read can pull in more than one request's worth of
+// bytes (the start of a pipelined request, or a body that arrived with its
+// head); anything past the current request stays buffered for the next call
+// instead of being read again or lost. Bodies over the spill threshold are
+// written to a temp file as they arrive, and only the head is returned.
+struct RequestReader {
+    buffer: Vec<u8>,
+    max_body_size: usize,
+    spill_threshold: Option<usize>,
+    read_timeout: Option<Duration>,
+    // How far the chunked body of the request at the front of the buffer has
+    // been decoded, while it is still arriving. Its encoded bytes are dropped
+    // from the buffer as they are decoded.
+    chunked: Option<ChunkedBody>,
+    // Where a chunked body that outgrew the spill threshold is being written.
+    chunked_spill: Option<(SpilledBody, File)>,
+    spilled: Option<SpilledBody>,
+    shutdown: watch::Receiver<bool>,
+    // Whether a request has been read already, so the connection is being
+    // kept open for another.
+    kept_alive: bool,
+}
+
+impl RequestReader {
+    fn new(
+        max_body_size: usize,
+        spill_threshold: Option<usize>,
+        read_timeout: Option<Duration>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_body_size,
+            spill_threshold,
+            read_timeout,
+            chunked: None,
+            chunked_spill: None,
+            spilled: None,
+            shutdown,
+            kept_alive: false,
+        }
+    }
+
+    // `idle_timeout` only bounds the wait for the first byte of a request, and
+    // `read_timeout` each wait for more of it after that. Returns `None` if the
+    // client closed the connection before starting another request.
+    async fn next_request(
+        &mut self,
+        tcp_stream: &mut TcpStream,
+        idle_timeout: Option<Duration>,
+    ) -> Result<Option<Vec<u8>>, ReadError> {
+        let mut buf = [0; 4096];
+        let kept_alive = std::mem::replace(&mut self.kept_alive, true);
+        self.spilled = None;
+
+        loop {
+            // Stray line breaks before a request line are ignored (RFC 9112
+            // section 2.2) rather than being taken for the end of an empty
+            // head.
+            let leading_breaks = self
+                .buffer
+                .iter()
+                .take_while(|&&byte| byte == b'\r' || byte == b'\n')
+                .count();
+            self.buffer.drain(0..leading_breaks);
+
+            match self.framing()? {
+                Some((head_end, Framing::Length(declared))) => {
+                    if self
+                        .spill_threshold
+                        .is_some_and(|threshold| declared > threshold)
+                    {
+                        return self.spill(tcp_stream, head_end, declared).await.map(Some);
+                    }
+                    if self.buffer.len() >= head_end + declared {
+                        return Ok(Some(self.buffer.drain(0..head_end + declared).collect()));
+                    }
+                }
+                Some((head_end, Framing::Chunked)) => {
+                    if let Some(request) = self.advance_chunked(head_end).await? {
+                        return Ok(Some(request));
+                    }
+                }
+                None => {}
+            }
+
+            let bytes_read = if self.buffer.is_empty() {
+                self.read_start(tcp_stream, &mut buf, idle_timeout, kept_alive)
+                    .await?
+            } else {
+                self.read_more(tcp_stream, &mut buf).await?
+            };
+
+            if bytes_read == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                // A complete request always returns above, so whatever is left
+                // is cut short, and never handed on as if it were a request.
+                return Err(match self.framing()? {
+                    Some((head_end, Framing::Length(declared))) => ReadError::TruncatedBody {
+                        declared,
+                        received: self.buffer.len() - head_end,
+                    },
+                    Some((_, Framing::Chunked)) => ReadError::TruncatedChunkedBody,
+                    None => ReadError::TruncatedHead,
+                });
+            }
+            self.buffer.extend_from_slice(&buf[0..bytes_read]);
+        }
+    }
+
+    // The body spilled by the last call to `next_request`, if its request was
+    // returned without one.
+    fn take_spilled_body(&mut self) -> Option<SpilledBody> {
+        self.spilled.take()
+    }
+
+    // Where the head of the first request in the buffer ends and how its body
+    // is framed, once the head is all there. The framing is checked as soon as
+    // the head is in, before any of the body is read. The head is capped so
+    // that a client can't make us buffer without end by never finishing it.
+    fn framing(&self) -> Result<Option<(usize, Framing)>, ReadError> {
+        let head_end = match head_end(&self.buffer) {
+            Some(head_end) if head_end <= MAX_HEAD_SIZE => head_end,
+            None if self.buffer.len() <= MAX_HEAD_SIZE => return Ok(None),
+            _ => {
+                return Err(ReadError::HeadTooLarge {
+                    limit: MAX_HEAD_SIZE,
+                })
+            }
+        };
+        let head = String::from_utf8_lossy(&self.buffer[0..head_end]);
+
+        Ok(Some((head_end, framing(&head, self.max_body_size)?)))
+    }
+
+    // Waits for the first bytes of a request, for at most `idle_timeout`. A
+    // connection `kept_alive` after an earlier request also stops waiting when
+    // the server shuts down; a new one still gets its first request answered.
+    async fn read_start(
+        &mut self,
+        tcp_stream: &mut TcpStream,
+        buf: &mut [u8],
+        idle_timeout: Option<Duration>,
+        kept_alive: bool,
+    ) -> Result<usize, ReadError> {
+        let idle = async {
+            match idle_timeout {
+                Some(idle_timeout) => tokio::time::sleep(idle_timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let shutdown = async {
+            if kept_alive {
+                let _ = self.shutdown.wait_for(|&shutdown| shutdown).await;
+            } else {
+                std::future::pending().await
+            }
+        };
+
+        tokio::select! {
+            bytes_read = tcp_stream.read(buf) => Ok(bytes_read?),
+            () = idle => Err(ReadError::Idle),
+            () = shutdown => Err(ReadError::ShuttingDown),
+        }
+    }
+
+    // Reads more of a request that has already started, giving up once the
+    // client has sent nothing for the read timeout.
+    async fn read_more(
+        &self,
+        tcp_stream: &mut TcpStream,
+        buf: &mut [u8],
+    ) -> Result<usize, ReadError> {
+        match self.read_timeout {
+            Some(read_timeout) => tokio::time::timeout(read_timeout, tcp_stream.read(buf))
+                .await
+                .map_err(|_| ReadError::Timeout)?
+                .map_err(ReadError::Io),
+            None => Ok(tcp_stream.read(buf).await?),
+        }
+    }
+
+    // Returns the head of the first request in the buffer, and writes its
+    // `declared` bytes of body to a temp file as they are read. The file is
+    // removed again if the body is cut short.
+    async fn spill(
+        &mut self,
+        tcp_stream: &mut TcpStream,
+        head_end: usize,
+        declared: usize,
+    ) -> Result<Vec<u8>, ReadError> {
+        let head = self.buffer.drain(0..head_end).collect();
+        let (spilled, mut file) = SpilledBody::create(declared)
+            .await
+            .map_err(ReadError::Spill)?;
+        let mut buf = vec![0; 64 * 1024];
+        let mut received = 0;
+
+        loop {
+            let take = self.buffer.len().min(declared - received);
+            file.write_all(&self.buffer[0..take])
+                .await
+                .map_err(ReadError::Spill)?;
+            self.buffer.drain(0..take);
+            received += take;
+            if received == declared {
+                break;
+            }
+
+            let bytes_read = self.read_more(tcp_stream, &mut buf).await?;
+            if bytes_read == 0 {
+                return Err(ReadError::TruncatedBody { declared, received });
+            }
+            self.buffer.extend_from_slice(&buf[0..bytes_read]);
+        }
+
+        file.flush().await.map_err(ReadError::Spill)?;
+        self.spilled = Some(spilled);
+        Ok(head)
+    }
+
+    // Decodes whatever more of the chunked body after `head_end` has arrived.
+    // Once the last chunk and the trailers are in, removes the request from
+    // the buffer and hands it on decoded, with the head rewritten to match:
+    // Transfer-Encoding dropped and the decoded length as Content-Length. The
+    // trailers count towards the head's size cap.
+    async fn advance_chunked(&mut self, head_end: usize) -> Result<Option<Vec<u8>>, ReadError> {
+        let chunked = self.chunked.get_or_insert_with(ChunkedBody::default);
+        let (consumed, done) = chunked.advance(
+            &self.buffer[head_end..],
+            self.max_body_size,
+            MAX_HEAD_SIZE - head_end,
+        )?;
+        self.buffer.drain(head_end..head_end + consumed);
+
+        let outgrown = self
+            .spill_threshold
+            .is_some_and(|threshold| chunked.len > threshold);
+        if outgrown && self.chunked_spill.is_none() {
+            let created = SpilledBody::create(0).await.map_err(ReadError::Spill)?;
+            self.chunked_spill = Some(created);
+        }
+        if let Some((_, file)) = &mut self.chunked_spill {
+            file.write_all(&chunked.decoded)
+                .await
+                .map_err(ReadError::Spill)?;
+            chunked.decoded.clear();
+        }
+
+        if !done {
+            return Ok(None);
+        }
+
+        let chunked = self.chunked.take().unwrap_or_default();
+        let head = String::from_utf8_lossy(&self.buffer[0..head_end]).into_owned();
+        let mut request = dechunked_head(&head, chunked.len).into_bytes();
+        self.buffer.drain(0..head_end);
+
+        match self.chunked_spill.take() {
+            Some((spilled, mut file)) => {
+                file.flush().await.map_err(ReadError::Spill)?;
+                self.spilled = Some(spilled.with_len(chunked.len));
+            }
+            None => request.extend_from_slice(&chunked.decoded),
+        }
+        Ok(Some(request))
+    }
+}
+
+// How the body of a request is delimited (RFC 9112 section 6.3).
+enum Framing {
+    Length(usize),
+    Chunked,
+}
+
+// Transfer-Encoding is read as a list of codings. `identity` means no coding at
+// all, so a list of only that falls back to Content-Length. Otherwise the only
+// coding understood is a single `chunked`; anything else gets a 501, as RFC
+// 9112 asks. A request with both headers is rejected rather than letting one
+// win, since that disagreement is what request smuggling relies on.
+fn framing(head: &str, limit: usize) -> Result<Framing, ReadError> {
+    let codings: Vec<String> = header_values(head, "transfer-encoding")
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity")
+        .collect();
+
+    if codings.is_empty() {
+        return declared_content_length(head, limit).map(Framing::Length);
+    }
+
+    if let Some(unknown) = codings.iter().find(|coding| *coding != "chunked") {
+        return Err(ReadError::UnsupportedTransferCoding(unknown.clone()));
+    }
+    if codings.len() > 1 {
+        return Err(ReadError::InvalidTransferEncoding(
+            "chunked applied more than once",
+        ));
+    }
+    if header_values(head, "content-length").next().is_some() {
+        return Err(ReadError::InvalidTransferEncoding(
+            "sent together with Content-Length",
+        ));
+    }
+
+    Ok(Framing::Chunked)
+}
+
+// Content-Length must be plain digits; signs, whitespace inside the number and
+// lists are rejected rather than guessed at. Several headers are only accepted
+// when they all agree. Lengths over `limit` (including ones too big for usize)
+// are rejected before any of the body is read.
+fn declared_content_length(head: &str, limit: usize) -> Result<usize, ReadError> {
+    let mut declared = None;
+
+    for value in header_values(head, "content-length") {
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(ReadError::InvalidContentLength);
+        }
+
+        let length = match value.parse::<usize>() {
+            Ok(length) if length <= limit => length,
+            _ => return Err(ReadError::BodyTooLarge { limit }),
+        };
+
+        if declared.is_some_and(|declared| declared != length) {
+            return Err(ReadError::InvalidContentLength);
+        }
+        declared = Some(length);
+    }
+
+    Ok(declared.unwrap_or(0))
+}
+
+// The trimmed values of every `name` header in a raw request head.
+fn header_values<'a>(head: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> {
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(move |(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// Decodes a chunked body as it arrives. The caller drops each consumed byte of
+// the encoded body, and progress (including how far an unfinished line has
+// been searched) is kept between reads, so each byte is only looked at once
+// however many reads it takes. Chunk extensions and trailer fields are
+// skipped. Lines may end in a bare LF, like the head.
+#[derive(Default)]
+struct ChunkedBody {
+    state: ChunkState,
+    // How much of the unfinished line at the front of the input has already
+    // been searched for its line ending.
+    scanned: usize,
+    // Decoded data not yet taken by the caller.
+    decoded: Vec<u8>,
+    // The length of the whole decoded body so far.
+    len: usize,
+    trailers_len: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+enum ChunkState {
+    #[default]
+    Size,
+    // Waiting for this many more bytes of chunk data.
+    Data(usize),
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+// A line running on past the limit it was read with.
+struct LineTooLong;
+
+impl ChunkedBody {
+    // Decodes as much of `input`, the encoded body received and not yet
+    // consumed, as it can. Returns how many bytes of it were consumed and
+    // whether the last chunk and the trailers are all in. The decoded body is
+    // held to `limit` and the trailers to `trailers_limit` bytes.
+    fn advance(
+        &mut self,
+        input: &[u8],
+        limit: usize,
+        trailers_limit: usize,
+    ) -> Result<(usize, bool), ReadError> {
+        let mut pos = 0;
+        let line_too_long = |_| ReadError::ChunkLineTooLong {
+            limit: MAX_CHUNK_LINE,
+        };
+
+        loop {
+            match self.state {
+                ChunkState::Size => {
+                    let Some((line, next)) = self
+                        .next_line(&input[pos..], MAX_CHUNK_LINE)
+                        .map_err(line_too_long)?
+                    else {
+                        return Ok((pos, false));
+                    };
+                    let size = std::str::from_utf8(line)
+                        .ok()
+                        .and_then(|line| line.split(';').next())
+                        .map(str::trim)
+                        .filter(|size| {
+                            !size.is_empty() && size.bytes().all(|byte| byte.is_ascii_hexdigit())
+                        })
+                        .ok_or(ReadError::MalformedChunk)?;
+                    let size = match usize::from_str_radix(size, 16) {
+                        Ok(size) if size <= limit - self.len => size,
+                        _ => return Err(ReadError::BodyTooLarge { limit }),
+                    };
+
+                    pos += next;
+                    self.len += size;
+                    self.state = if size == 0 {
+                        ChunkState::Trailers
+                    } else {
+                        ChunkState::Data(size)
+                    };
+                }
+                ChunkState::Data(remaining) => {
+                    let available = remaining.min(input.len() - pos);
+                    self.decoded.extend_from_slice(&input[pos..pos + available]);
+                    pos += available;
+
+                    if available < remaining {
+                        self.state = ChunkState::Data(remaining - available);
+                        return Ok((pos, false));
+                    }
+                    self.state = ChunkState::DataEnd;
+                }
+                ChunkState::DataEnd => match self
+                    .next_line(&input[pos..], MAX_CHUNK_LINE)
+                    .map_err(line_too_long)?
+                {
+                    Some(([], next)) => {
+                        pos += next;
+                        self.state = ChunkState::Size;
+                    }
+                    Some(_) => return Err(ReadError::MalformedChunk),
+                    None => return Ok((pos, false)),
+                },
+                ChunkState::Trailers => {
+                    let head_too_large = |_| ReadError::HeadTooLarge {
+                        limit: MAX_HEAD_SIZE,
+                    };
+                    let Some((line, next)) = self
+                        .next_line(&input[pos..], trailers_limit - self.trailers_len)
+                        .map_err(head_too_large)?
+                    else {
+                        return Ok((pos, false));
+                    };
+
+                    pos += next;
+                    self.trailers_len += next;
+                    if line.is_empty() {
+                        self.state = ChunkState::Done;
+                    }
+                }
+                ChunkState::Done => return Ok((pos, true)),
+            }
+        }
+    }
+
+    // The line at the start of `input` without its line ending, and its length
+    // with it, once the line ending is in. Only bytes not searched by an
+    // earlier call are looked at. A line longer than `max` bytes, line ending
+    // included, is an error whether or not it has ended.
+    fn next_line<'a>(
+        &mut self,
+        input: &'a [u8],
+        max: usize,
+    ) -> Result<Option<(&'a [u8], usize)>, LineTooLong> {
+        let searchable = input.len().min(max);
+        match input[self.scanned..searchable]
+            .iter()
+            .position(|&byte| byte == b'\n')
+        {
+            Some(offset) => {
+                let end = self.scanned + offset;
+                self.scanned = 0;
+                let line = &input[..end];
+                Ok(Some((line.strip_suffix(b"\r").unwrap_or(line), end + 1)))
+            }
+            None if input.len() >= max => Err(LineTooLong),
+            None => {
+                self.scanned = input.len();
+                Ok(None)
+            }
+        }
+    }
+}
+
+// The request head with Transfer-Encoding replaced by the decoded body's
+// Content-Length, keeping the line endings the client used.
+fn dechunked_head(head: &str, length: usize) -> String {
+    let mut lines: Vec<&str> = head.split_inclusive('\n').collect();
+    let blank_line = lines.pop().unwrap_or("\r\n");
+    let line_ending = if blank_line.starts_with('\r') {
+        "\r\n"
+    } else {
+        "\n"
+    };
+
+    let mut rewritten: String = lines
+        .into_iter()
+        .filter(|line| {
+            !line
+                .split_once(':')
+                .is_some_and(|(key, _)| key.trim().eq_ignore_ascii_case("transfer-encoding"))
+        })
+        .collect();
+    rewritten.push_str(&format!("Content-Length: {}{}", length, line_ending));
+    rewritten.push_str(blank_line);
+    rewritten
+}
+
+fn is_ipv4_mapped(peer: &SocketAddr) -> bool {
+    match peer.ip() {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().is_some(),
+        IpAddr::V4(_) => false,
+    }
+}
+
+// The peer went away before we got to it, nothing to back off from.
+fn is_connection_error(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+    )
+}
+
+// The listener itself is unusable, retrying will never succeed. Anything else
+// (e.g. running out of file descriptors) is treated as transient.
+fn is_fatal_accept_error(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::InvalidInput | ErrorKind::PermissionDenied | ErrorKind::Unsupported
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BearerAuth;
+    use pretty_assertions::assert_eq;
+    use std::net::SocketAddr;
+    use tokio::io::AsyncWriteExt;
+
+    // Starts a server on a free port with whatever `configure` registers.
+    async fn start(configure: impl FnOnce(&mut Server)) -> SocketAddr {
+        let mut server = Server::new("127.0.0.1:0").await.unwrap();
+        configure(&mut server);
+        let addr = server.local_addr();
+        tokio::spawn(Server::listen(Arc::new(server)));
+        addr
+    }
+
+    // Sends `request`, closes our side and returns the first response the
+    // server sent back before closing.
+    async fn exchange(addr: SocketAddr, request: &[u8]) -> Reply {
+        parse_reply(&exchange_raw(addr, request).await)
+    }
+
+    // Like `exchange`, returning every response, e.g. to pipelined requests.
+    async fn exchange_all(addr: SocketAddr, request: &[u8]) -> Vec<Reply> {
+        parse_replies(&exchange_raw(addr, request).await)
+    }
+
+    // Like `exchange`, returning the response exactly as it was sent.
+    async fn exchange_raw(addr: SocketAddr, request: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    #[derive(Debug)]
+    struct Reply {
+        status: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Reply {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.body).into_owned()
+        }
+    }
+
+    fn parse_reply(raw: &[u8]) -> Reply {
+        parse_replies(raw).remove(0)
+    }
+
+    // Splits `raw` into the responses in it. A body ends after its
+    // `Content-Length` (or with what is there, for HEAD), or after its last
+    // chunk, and is kept chunk-encoded; otherwise it runs to the close.
+    fn parse_replies(mut raw: &[u8]) -> Vec<Reply> {
+        let mut replies = Vec::new();
+
+        while !raw.is_empty() {
+            let end = head_end(raw).expect("incomplete response head");
+            let head = String::from_utf8(raw[..end].to_vec()).unwrap();
+
+            let mut lines = head.lines();
+            let status = lines.next().unwrap().to_string();
+            let headers = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(key, value)| (key.to_string(), value.trim().to_string()))
+                .collect();
+            let mut reply = Reply {
+                status,
+                headers,
+                body: Vec::new(),
+            };
+
+            let rest = &raw[end..];
+            let body_len = if let Some(length) = reply.header("Content-Length") {
+                length.parse::<usize>().unwrap().min(rest.len())
+            } else if reply.header("Transfer-Encoding") == Some("chunked") {
+                let (consumed, done) = ChunkedBody::default()
+                    .advance(rest, usize::MAX, usize::MAX)
+                    .unwrap();
+                assert!(done, "incomplete chunked body");
+                consumed
+            } else {
+                rest.len()
+            };
+            reply.body = rest[..body_len].to_vec();
+            replies.push(reply);
+            raw = &rest[body_len..];
+        }
+
+        replies
+    }
+
+    // A handler answering with `body`, and the request body after it if any.
+    fn reply(body: &'static str) -> Handler {
+        Box::new(move |req| {
+            let mut response = Response::new();
+            response.set_body(&format!("{}{}", body, String::from_utf8_lossy(&req.body)));
+            Ok(response)
+        })
+    }
+
+    #[tokio::test]
+    async fn body_stops_at_its_content_length() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/item", Verb::Delete), reply("deleted "))
+                .unwrap();
+        })
+        .await;
+
+        let replies = exchange_all(
+            addr,
+            b"DELETE /item HTTP/1.1\r\nHost: x\r\nContent-Length: 14\r\n\r\nGET /nope HTTP\
+              DELETE /item HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].status, "HTTP/1.1 200 OK");
+        assert_eq!(replies[0].header("Content-Length"), Some("22"));
+        assert_eq!(replies[0].text(), "deleted GET /nope HTTP");
+        assert_eq!(replies[1].text(), "deleted ");
+    }
+
+    #[tokio::test]
+    async fn route_content_type_fills_in_for_the_handler() {
+        let addr = start(|server| {
+            server
+                .register_route(
+                    Route::new("/plain", Verb::Get).with_content_type("text/plain"),
+                    reply("plain"),
+                )
+                .unwrap();
+            server
+                .register_route(
+                    Route::new("/html", Verb::Get).with_content_type("text/plain"),
+                    Box::new(|_| {
+                        let mut response = Response::new();
+                        response.set_header("Content-Type", "text/html");
+                        Ok(response)
+                    }),
+                )
+                .unwrap();
+        })
+        .await;
+
+        let plain = exchange(addr, b"GET /plain HTTP/1.1\r\n\r\n").await;
+        let html = exchange(addr, b"GET /html HTTP/1.1\r\n\r\n").await;
+
+        assert_eq!(plain.header("Content-Type"), Some("text/plain"));
+        assert_eq!(html.header("Content-Type"), Some("text/html"));
+    }
+
+    #[tokio::test]
+    async fn filters_can_rewrite_paths_and_answer_requests() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/new", Verb::Get), reply("new"))
+                .unwrap();
+            server.register_filter(Box::new(|req: &mut Request| {
+                if let Some(rest) = req.path.strip_prefix("/old") {
+                    req.path = format!("/new{}", rest);
+                }
+                None
+            }));
+            server.register_filter(Box::new(|req: &mut Request| {
+                (req.path == "/blocked").then(|| {
+                    let mut response = Response::new();
+                    response.set_status_code(403);
+                    response.set_status_text("Forbidden");
+                    response.set_body("No");
+                    response
+                })
+            }));
+        })
+        .await;
+
+        let rewritten = exchange(addr, b"GET /old HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let blocked = exchange(addr, b"GET /blocked HTTP/1.1\r\nHost: x\r\n\r\n").await;
+
+        assert_eq!(rewritten.status, "HTTP/1.1 200 OK");
+        assert_eq!(rewritten.text(), "new");
+        assert_eq!(blocked.status, "HTTP/1.1 403 Forbidden");
+        assert_eq!(blocked.text(), "No");
+    }
+
+    #[tokio::test]
+    async fn routes_match_the_decoded_path() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/hello world", Verb::Get), reply("hi"))
+                .unwrap();
+            server
+                .register_route(Route::new("/a/b", Verb::Get), reply("a/b"))
+                .unwrap();
+        })
+        .await;
+
+        let spaced = exchange(addr, b"GET /hello%20world HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let escaped_slash = exchange(addr, b"GET /a%2Fb HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let escaped_letter = exchange(addr, b"GET /%61/b HTTP/1.1\r\nHost: x\r\n\r\n").await;
+
+        assert_eq!(spaced.text(), "hi");
+        assert_eq!(escaped_slash.status, "HTTP/1.1 404 Not Found");
+        assert_eq!(escaped_letter.text(), "a/b");
+    }
+
+    #[tokio::test]
+    async fn no_content_drops_the_body_and_its_length() {
+        let addr = start(|server| {
+            server
+                .register_route(
+                    Route::new("/gone", Verb::Delete),
+                    Box::new(|_| {
+                        let mut response = Response::no_content();
+                        response.set_header("Content-Length", "4");
+                        response.set_body("gone");
+                        Ok(response)
+                    }),
+                )
+                .unwrap();
+        })
+        .await;
+
+        let reply = exchange(addr, b"DELETE /gone HTTP/1.1\r\nHost: x\r\n\r\n").await;
+
+        assert_eq!(reply.status, "HTTP/1.1 204 No Content");
+        assert_eq!(reply.header("Content-Length"), None);
+        assert_eq!(reply.text(), "");
+    }
+
+    const UPGRADE: &[u8] = b"GET /ws HTTP/1.1\r\nHost: x\r\nConnection: Upgrade\r\n\
+        Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+
+    #[tokio::test]
+    async fn websocket_upgrade_is_answered_with_the_accept_key() {
+        let addr = start(|server| {
+            server.websocket("/ws", |_stream| async {});
+        })
+        .await;
+
+        let reply = exchange(addr, &[UPGRADE, b"\r\n"].concat()).await;
+
+        assert_eq!(reply.status, "HTTP/1.1 101 Switching Protocols");
+        assert_eq!(
+            reply.header("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+    }
+
+    #[tokio::test]
+    async fn graceful_close_sends_the_response_before_draining() {
+        let addr = start(|server| {
+            server.set_graceful_close(Some(Duration::from_secs(5)));
+            server
+                .register_route(Route::new("/item", Verb::Get), reply("item"))
+                .unwrap();
+        })
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /item HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+
+        assert_eq!(parse_reply(&response).text(), "item");
+        // The server is still draining, so what we send now is read, not
+        // answered with a reset.
+        stream.write_all(b"late bytes").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_refuses_new_connections_and_answers_accepted_ones() {
+        let mut server = Server::new("127.0.0.1:0").await.unwrap();
+        server
+            .register_route(Route::new("/slow", Verb::Post), reply("done "))
+            .unwrap();
+        server
+            .register_route(Route::new("/fast", Verb::Get), reply("fast"))
+            .unwrap();
+        let addr = server.local_addr();
+        let (trigger, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let listening = tokio::spawn(Server::listen_until(Arc::new(server), async {
+            let _ = shutdown.await;
+        }));
+
+        let mut slow = TcpStream::connect(addr).await.unwrap();
+        slow.write_all(b"POST /slow HTTP/1.1\r\nContent-Length: 4\r\n\r\nab")
+            .await
+            .unwrap();
+        // Connections are accepted in order, so once a later one has been
+        // answered the slow one is in flight.
+        let fast = exchange(addr, b"GET /fast HTTP/1.1\r\n\r\n").await;
+        assert_eq!(fast.text(), "fast");
+
+        trigger.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while TcpStream::connect(addr).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("the listener is still accepting");
+
+        slow.write_all(b"cd").await.unwrap();
+        let mut response = Vec::new();
+        slow.read_to_end(&mut response).await.unwrap();
+        assert_eq!(parse_reply(&response).text(), "done abcd");
+        listening.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn body_shorter_than_its_content_length_is_rejected() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/upload", Verb::Post), reply(""))
+                .unwrap();
+        })
+        .await;
+
+        let reply = exchange(
+            addr,
+            b"POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nabc",
+        )
+        .await;
+
+        assert_eq!(reply.status, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn head_cut_short_is_rejected_without_routing_it() {
+        let routed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let addr = start(|server| {
+            let routed = Arc::clone(&routed);
+            server
+                .register_route(
+                    Route::new("/upload", Verb::Post),
+                    Box::new(move |_| {
+                        routed.store(true, std::sync::atomic::Ordering::SeqCst);
+                        Ok(Response::new())
+                    }),
+                )
+                .unwrap();
+        })
+        .await;
+
+        let half_head = exchange(
+            addr,
+            b"POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n",
+        )
+        .await;
+        let bare_verb = exchange(addr, b"GET").await;
+
+        assert_eq!(half_head.status, "HTTP/1.1 400 Bad Request");
+        assert_eq!(bare_verb.status, "HTTP/1.1 400 Bad Request");
+        assert!(!routed.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn connection_closed_without_a_request_gets_no_answer() {
+        let addr = start(|_| {}).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn binding_a_taken_address_is_an_error() {
+        let addr = start(|_| {}).await;
+
+        let err = Server::new(&addr.to_string()).await.err().unwrap();
+
+        assert_eq!(err.to_string(), format!("problem listening on {}", addr));
+    }
+
+    #[tokio::test]
+    async fn unknown_verbs_are_not_implemented_and_strict_mode_rejects_sloppy_heads() {
+        let addr = start(|server| {
+            server.set_parse_mode(ParseMode::Strict);
+            server
+                .register_route(Route::new("/item", Verb::Get), reply("item"))
+                .unwrap();
+        })
+        .await;
+
+        let unknown = exchange(addr, b"BREW /item HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let bare_lf = exchange(addr, b"GET /item HTTP/1.1\nHost: x\n\n").await;
+        let strict = exchange(addr, b"GET /item HTTP/1.1\r\nHost: x\r\n\r\n").await;
+
+        assert_eq!(unknown.status, "HTTP/1.1 501 Not Implemented");
+        assert_eq!(bare_lf.status, "HTTP/1.1 400 Bad Request");
+        assert_eq!(strict.text(), "item");
+    }
+
+    // A handler streaming `count` messages of `size` bytes each.
+    fn stream(count: usize, size: usize) -> Handler {
+        Box::new(move |_| {
+            let (chunks, receiver) = tokio::sync::mpsc::channel(4);
+            tokio::spawn(async move {
+                for i in 0..count {
+                    let byte = b'a' + (i % 26) as u8;
+                    if chunks.send(vec![byte; size]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let mut response = Response::new();
+            response.set_body_stream(receiver);
+            Ok(response)
+        })
+    }
+
+    fn streamed_body(count: usize, size: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| vec![b'a' + (i % 26) as u8; size])
+            .collect()
+    }
+
+    fn dechunk(raw: &[u8]) -> Vec<u8> {
+        let mut chunked = ChunkedBody::default();
+        let (_, done) = chunked.advance(raw, usize::MAX, usize::MAX).unwrap();
+        assert!(done, "incomplete chunked body");
+        chunked.decoded
+    }
+
+    #[tokio::test]
+    async fn short_streams_are_sent_with_a_content_length() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/short", Verb::Get), stream(3, 10))
+                .unwrap();
+            server
+                .register_route(Route::new("/long", Verb::Get), stream(100, 1000))
+                .unwrap();
+        })
+        .await;
+
+        let short = exchange(addr, b"GET /short HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let long = exchange(addr, b"GET /long HTTP/1.1\r\nHost: x\r\n\r\n").await;
+
+        assert_eq!(short.header("Content-Length"), Some("30"));
+        assert_eq!(short.header("Transfer-Encoding"), None);
+        assert_eq!(short.body, streamed_body(3, 10));
+        assert_eq!(long.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(dechunk(&long.body), streamed_body(100, 1000));
+    }
+
+    #[tokio::test]
+    async fn stream_buffering_can_be_turned_off() {
+        let addr = start(|server| {
+            server.set_stream_buffer_limit(0);
+            server
+                .register_route(Route::new("/short", Verb::Get), stream(3, 10))
+                .unwrap();
+        })
+        .await;
+
+        let short = exchange(addr, b"GET /short HTTP/1.1\r\nHost: x\r\n\r\n").await;
+
+        assert_eq!(short.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(dechunk(&short.body), streamed_body(3, 10));
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed_cleanly_without_a_response() {
+        let addr = start(|server| {
+            server.set_idle_timeout(Some(Duration::from_millis(50)));
+        })
+        .await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        // A reset would make this an error rather than an empty read.
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received));
+
+        assert_eq!(read.await.unwrap().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrades_are_authenticated() {
+        let addr = start(|server| {
+            server.set_authenticator(Some(Box::new(BearerAuth::new("ws", &["secret"]))));
+            server.websocket("/ws", |_stream| async {});
+        })
+        .await;
+
+        let refused = exchange(addr, &[UPGRADE, b"\r\n"].concat()).await;
+        assert_eq!(refused.status, "HTTP/1.1 401 Unauthorized");
+
+        let authorized = [UPGRADE, b"Authorization: Bearer secret\r\n\r\n"].concat();
+        let accepted = exchange(addr, &authorized).await;
+        assert_eq!(accepted.status, "HTTP/1.1 101 Switching Protocols");
+        assert_eq!(
+            accepted.header("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_favicon_file_falls_back_to_no_content() {
+        let addr = start(|server| {
+            server.set_favicon(Some(Favicon::File(PathBuf::from("/no/such/favicon.ico"))));
+        })
+        .await;
+
+        let reply = exchange(addr, b"GET /favicon.ico HTTP/1.1\r\nHost: x\r\n\r\n").await;
+
+        assert_eq!(reply.status, "HTTP/1.1 204 No Content");
+        assert!(reply.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn favicon_file_is_served_with_its_type() {
+        let path = std::env::temp_dir().join(format!("favicon-{}.png", std::process::id()));
+        std::fs::write(&path, b"\x89PNG icon").unwrap();
+        let icon = path.clone();
+        let addr = start(move |server| server.set_favicon(Some(Favicon::File(icon)))).await;
+
+        let reply = exchange(addr, b"GET /favicon.ico HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reply.header("Content-Type"), Some("image/png"));
+        assert_eq!(reply.body, b"\x89PNG icon");
+    }
+
+    #[tokio::test]
+    async fn body_sent_with_the_head_is_not_waited_for_again() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/echo", Verb::Post), reply(""))
+                .unwrap();
+        })
+        .await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        // One write, and the connection stays open: the server has to answer
+        // from what it already buffered.
+        stream
+            .write_all(b"POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while !received.ends_with(b"\r\n\r\nhello") {
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf));
+            let bytes_read = read.await.unwrap().unwrap();
+            assert_ne!(bytes_read, 0);
+            received.extend_from_slice(&buf[..bytes_read]);
+        }
+        assert!(received.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    async fn auto_methods_server(auto_head: bool, auto_options: bool) -> SocketAddr {
+        start(|server| {
+            server.set_auto_head(auto_head);
+            server.set_auto_options(auto_options);
+            server
+                .register_route(Route::new("/page", Verb::Get), reply("page"))
+                .unwrap();
+            server
+                .register_route(Route::new("/page", Verb::Post), reply(""))
+                .unwrap();
+            server
+                .register_route(
+                    Route::new("/hidden", Verb::Get).without_auto_methods(),
+                    reply("hidden"),
+                )
+                .unwrap();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn head_and_options_are_answered_automatically_by_default() {
+        let addr = auto_methods_server(true, true).await;
+
+        let head = exchange_raw(addr, b"HEAD /page HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nContent-Length: 4\r\n"));
+        assert!(head.ends_with("\r\n\r\n"));
+
+        let options = exchange(addr, b"OPTIONS /page HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert_eq!(options.status, "HTTP/1.1 204 No Content");
+        assert_eq!(options.header("Allow"), Some("GET, POST, HEAD, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn routes_can_opt_out_of_automatic_methods() {
+        let addr = auto_methods_server(true, true).await;
+
+        let head = exchange_raw(addr, b"HEAD /hidden HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(head.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+
+        let options = exchange(addr, b"OPTIONS /hidden HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert_eq!(options.status, "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn head_and_options_can_be_turned_off() {
+        let addr = auto_methods_server(false, false).await;
+
+        // The path is still routed, so the methods it does take are listed.
+        let head = exchange(addr, b"HEAD /page HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert_eq!(head.status, "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(head.header("Allow"), Some("GET, POST"));
+
+        let options = exchange(addr, b"OPTIONS /page HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert_eq!(options.status, "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(options.header("Allow"), Some("GET, POST"));
+    }
+
+    #[test]
+    fn content_length_must_be_plain_digits_within_the_limit() {
+        let length = |value: &str| {
+            let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", value);
+            declared_content_length(&head, 1024)
+        };
+
+        assert!(matches!(length("12"), Ok(12)));
+        assert!(matches!(
+            length("1025"),
+            Err(ReadError::BodyTooLarge { limit: 1024 })
+        ));
+        assert!(matches!(
+            length("99999999999999999999"),
+            Err(ReadError::BodyTooLarge { .. })
+        ));
+        for invalid in ["-1", "+1", "", "1 2", "1,1", "0x10"] {
+            assert!(
+                matches!(length(invalid), Err(ReadError::InvalidContentLength)),
+                "{:?}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_content_lengths_must_agree() {
+        let head = "POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\n";
+        assert!(matches!(declared_content_length(head, 1024), Ok(3)));
+
+        let head = "POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n";
+        assert!(matches!(
+            declared_content_length(head, 1024),
+            Err(ReadError::InvalidContentLength)
+        ));
+    }
+
+    #[tokio::test]
+    async fn huge_and_negative_content_lengths_are_refused() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/", Verb::Post), reply(""))
+                .unwrap();
+        })
+        .await;
+
+        let huge = exchange(
+            addr,
+            b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 99999999999999999999\r\n\r\n",
+        )
+        .await;
+        assert_eq!(huge.status, "HTTP/1.1 413 Content Too Large");
+
+        let negative = exchange(
+            addr,
+            b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: -1\r\n\r\n",
+        )
+        .await;
+        assert_eq!(negative.status, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn echo_endpoint_is_authenticated_and_redacts_credentials() {
+        let addr = start(|server| {
+            server.set_debug(true);
+            server.set_authenticator(Some(Box::new(BearerAuth::new("api", &["secret"]))));
+        })
+        .await;
+
+        let refused = exchange(addr, b"GET /__echo HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let echoed = exchange(
+            addr,
+            b"GET /__echo HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer secret\r\n\
+              Cookie: session=abc\r\n\r\n",
+        )
+        .await;
+
+        assert_eq!(refused.status, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(echoed.status, "HTTP/1.1 200 OK");
+        let echo = echoed.text();
+        assert!(
+            echo.contains("[\"authorization\",\"[redacted]\"]"),
+            "{}",
+            echo
+        );
+        assert!(echo.contains("[\"cookie\",\"[redacted]\"]"), "{}", echo);
+        assert!(
+            !echo.contains("secret") && !echo.contains("abc"),
+            "{}",
+            echo
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_write_drops_the_connection_and_its_pipelined_requests() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let addr = start(move |server| {
+            server
+                .register_route(
+                    Route::new("/big", Verb::Get),
+                    Box::new(|_| {
+                        let mut response = Response::new();
+                        response.set_body_bytes(&vec![b'x'; 16 * 1024 * 1024]);
+                        Ok(response)
+                    }),
+                )
+                .unwrap();
+            server
+                .register_route(
+                    Route::new("/count", Verb::Get),
+                    Box::new(move |_| {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        Ok(Response::new())
+                    }),
+                )
+                .unwrap();
+        })
+        .await;
+
+        // Ask for far more than the socket buffers hold, then reset the
+        // connection without reading any of it.
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(
+                b"GET /big HTTP/1.1\r\nHost: x\r\n\r\n\
+                  GET /count HTTP/1.1\r\nHost: x\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // A zero linger is what makes the close a reset. Newer tokio versions
+        // deprecate it for blocking on drop, which it can't do with no delay.
+        #[allow(deprecated)]
+        client.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(handled.load(Ordering::SeqCst), 0);
+
+        let reply = exchange(addr, b"GET /count HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert_eq!(reply.status, "HTTP/1.1 200 OK");
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_spill_threshold_are_kept_in_a_temp_file() {
+        use std::io::Read;
+
+        let spilled = Arc::new(Mutex::new(Vec::new()));
+        let seen = spilled.clone();
+        let addr = start(move |server| {
+            server.set_spill_threshold(Some(16));
+            server
+                .register_route(
+                    Route::new("/upload", Verb::Post),
+                    Box::new(move |req| {
+                        let path = req.spilled_body_path().map(PathBuf::from);
+                        seen.lock().unwrap().push(path.clone());
+
+                        let mut body = String::new();
+                        req.body_reader()
+                            .unwrap()
+                            .read_to_string(&mut body)
+                            .unwrap();
+                        assert_eq!(req.body_len(), body.len());
+                        assert_eq!(req.body.is_empty(), path.is_some());
+
+                        let mut response = Response::new();
+                        response.set_body(&body);
+                        Ok(response)
+                    }),
+                )
+                .unwrap();
+        })
+        .await;
+
+        let small = exchange(
+            addr,
+            b"POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 16\r\n\r\nsixteen bytes!!!",
+        )
+        .await;
+        let large_body = "0123456789".repeat(1000);
+        let large = exchange(
+            addr,
+            format!(
+                "POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n{}",
+                large_body.len(),
+                large_body
+            )
+            .as_bytes(),
+        )
+        .await;
+
+        assert_eq!(small.text(), "sixteen bytes!!!");
+        assert_eq!(large.text(), large_body);
+
+        let spilled = spilled.lock().unwrap().clone();
+        assert_eq!(spilled[0], None);
+        let path = spilled[1].as_ref().expect("the large body was not spilled");
+        assert!(path.starts_with(std::env::temp_dir()));
+        // The request, and with it the file, is dropped just after the
+        // connection closes.
+        for _ in 0..50 {
+            if !path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!path.exists(), "{} was left behind", path.display());
+    }
+
+    #[tokio::test]
+    async fn a_spilled_body_can_be_moved_into_place() {
+        let target = std::env::temp_dir().join(format!(
+            "http-server-test-{}-spilled-upload",
+            std::process::id()
+        ));
+        let saved_to = target.clone();
+        let addr = start(move |server| {
+            server.set_spill_threshold(Some(4));
+            server
+                .register_async_route(
+                    Route::new("/upload", Verb::Post),
+                    Box::new(move |req| {
+                        let saved_to = saved_to.clone();
+                        Box::pin(async move {
+                            req.save_body(&saved_to).await.unwrap();
+                            Ok(Response::new())
+                        })
+                    }),
+                )
+                .unwrap();
+        })
+        .await;
+
+        let reply = exchange(
+            addr,
+            b"POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 11\r\n\r\nhello world",
+        )
+        .await;
+
+        assert_eq!(reply.status, "HTTP/1.1 200 OK");
+        assert_eq!(std::fs::read(&target).unwrap(), b"hello world");
+        std::fs::remove_file(&target).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_spilled_body_cut_short_is_rejected() {
+        let addr = start(|server| {
+            server.set_spill_threshold(Some(4));
+            server
+                .register_route(Route::new("/upload", Verb::Post), reply("unreachable"))
+                .unwrap();
+        })
+        .await;
+
+        let reply = exchange(
+            addr,
+            b"POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 100\r\n\r\nnot enough",
+        )
+        .await;
+
+        assert_eq!(reply.status, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn encoded_nul_is_a_bad_request() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/files/*path", Verb::Get), reply("file"))
+                .unwrap();
+        })
+        .await;
+
+        let reply = exchange(addr, b"GET /files/a%00.txt HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert_eq!(reply.status, "HTTP/1.1 400 Bad Request");
+        assert_eq!(
+            reply.text(),
+            "request target decodes to a control character"
+        );
+
+        let reply = exchange(addr, b"GET /files/a.txt HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert_eq!(reply.text(), "file");
+    }
+
+    fn framing_of(transfer_encoding: &[&str], content_length: Option<&str>) -> String {
+        let mut head = "POST / HTTP/1.1\r\n".to_string();
+        for value in transfer_encoding {
+            head.push_str(&format!("Transfer-Encoding: {}\r\n", value));
+        }
+        if let Some(value) = content_length {
+            head.push_str(&format!("Content-Length: {}\r\n", value));
+        }
+        head.push_str("\r\n");
+
+        match framing(&head, 1024) {
+            Ok(Framing::Length(length)) => format!("length {}", length),
+            Ok(Framing::Chunked) => "chunked".to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn transfer_encoding_combinations() {
+        assert_eq!(framing_of(&["identity"], Some("5")), "length 5");
+        assert_eq!(framing_of(&["chunked"], None), "chunked");
+        assert_eq!(framing_of(&["Identity, Chunked"], None), "chunked");
+        assert_eq!(framing_of(&["identity", "chunked"], None), "chunked");
+        assert_eq!(
+            framing_of(&["chunked, chunked"], None),
+            "invalid Transfer-Encoding: chunked applied more than once"
+        );
+        assert_eq!(
+            framing_of(&["gzip, chunked"], None),
+            "unsupported transfer coding \"gzip\""
+        );
+        assert_eq!(
+            framing_of(&["chunked"], Some("5")),
+            "invalid Transfer-Encoding: sent together with Content-Length"
+        );
+    }
+
+    // Feeds `encoded` to a decoder one byte at a time, dropping what it
+    // consumes the way `RequestReader` does. Returns the decoded body, what
+    // was left over and the most that was ever buffered.
+    fn decode_bytewise(encoded: &[u8]) -> (Vec<u8>, Vec<u8>, usize) {
+        let mut chunked = ChunkedBody::default();
+        let mut buffer = Vec::new();
+        let mut decoded = Vec::new();
+        let mut most_buffered = 0;
+
+        for &byte in encoded {
+            buffer.push(byte);
+            most_buffered = most_buffered.max(buffer.len());
+            let (consumed, done) = chunked.advance(&buffer, 1024, 1024).unwrap();
+            buffer.drain(0..consumed);
+            decoded.append(&mut chunked.decoded);
+            if done {
+                break;
+            }
+        }
+
+        (decoded, buffer, most_buffered)
+    }
+
+    #[test]
+    fn chunked_body_decodes_across_reads() {
+        let (decoded, left, _) =
+            decode_bytewise(b"5;ext=1\r\nhello\r\n7\n, world\n0\r\nTrailer: x\r\n\r\nGET");
+
+        assert_eq!(decoded, b"hello, world");
+        assert_eq!(left, b"");
+    }
+
+    #[test]
+    fn decoded_chunk_data_is_not_kept_buffered() {
+        let data = "x".repeat(1000);
+        let encoded = format!("{:x}\r\n{}\r\n0\r\n\r\n", data.len(), data);
+
+        let (decoded, _, most_buffered) = decode_bytewise(encoded.as_bytes());
+
+        assert_eq!(decoded, data.as_bytes());
+        // Only an unfinished line is ever held, never the chunk data.
+        assert!(most_buffered <= 5, "{} bytes buffered", most_buffered);
+    }
+
+    #[test]
+    fn chunked_body_over_the_limit_is_refused() {
+        let mut chunked = ChunkedBody::default();
+
+        assert!(matches!(
+            chunked.advance(b"8\r\n12345678\r\n9\r\n", 16, 1024),
+            Err(ReadError::BodyTooLarge { limit: 16 })
+        ));
+    }
+
+    #[test]
+    fn chunk_size_lines_are_capped_with_or_without_their_end() {
+        let extension = format!("5;{}", "x".repeat(MAX_CHUNK_LINE));
+
+        for encoded in [extension.clone(), format!("{}\r\nhello\r\n", extension)] {
+            let mut chunked = ChunkedBody::default();
+
+            assert!(matches!(
+                chunked.advance(encoded.as_bytes(), 1 << 20, 1024),
+                Err(ReadError::ChunkLineTooLong { .. })
+            ));
+        }
+
+        let mut chunked = ChunkedBody::default();
+        let fits = format!("5;{}\r\nhello\r\n", "x".repeat(MAX_CHUNK_LINE - 16));
+        assert!(chunked.advance(fits.as_bytes(), 1 << 20, 1024).is_ok());
+    }
+
+    #[test]
+    fn trailers_count_towards_the_head_limit() {
+        let trailers = format!("0\r\nX-Trailer: {}\r\n\r\n", "x".repeat(100));
+        let mut chunked = ChunkedBody::default();
+
+        assert!(matches!(
+            chunked.advance(trailers.as_bytes(), 1024, 64),
+            Err(ReadError::HeadTooLarge { .. })
+        ));
+
+        let mut chunked = ChunkedBody::default();
+        assert!(matches!(
+            chunked.advance(trailers.as_bytes(), 1024, 1024),
+            Ok((_, true))
+        ));
+    }
+
+    #[tokio::test]
+    async fn transfer_encodings_get_the_right_status() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/upload", Verb::Post), reply("got "))
+                .unwrap();
+        })
+        .await;
+        let long_trailer = format!(
+            "POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+             0\r\nX-Trailer: {}\r\n\r\n",
+            "x".repeat(MAX_HEAD_SIZE)
+        );
+        let long_size_line = format!(
+            "POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+             2;{}\r\nhi\r\n0\r\n\r\n",
+            "x".repeat(MAX_CHUNK_LINE)
+        );
+
+        let cases: [(&[u8], &str); 6] = [
+            (
+                b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: identity, chunked\r\n\r\n\
+                  2\r\nhi\r\n0\r\n\r\n",
+                "HTTP/1.1 200 OK",
+            ),
+            (
+                b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip\r\n\r\n",
+                "HTTP/1.1 501 Not Implemented",
+            ),
+            (
+                b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\
+                  Content-Length: 2\r\n\r\nhi",
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  2\r\nhi",
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (long_size_line.as_bytes(), "HTTP/1.1 400 Bad Request"),
+            (
+                long_trailer.as_bytes(),
+                "HTTP/1.1 431 Request Header Fields Too Large",
+            ),
+        ];
+
+        for (request, status) in cases {
+            let reply = exchange(addr, request).await;
+            assert_eq!(reply.status, status);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_are_decoded_for_the_handler() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/upload", Verb::Post), reply("got "))
+                .unwrap();
+        })
+        .await;
+
+        let reply = exchange(
+            addr,
+            b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        )
+        .await;
+
+        assert_eq!(reply.text(), "got hello world");
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_over_the_spill_threshold_are_spilled() {
+        use std::io::Read;
+
+        let addr = start(|server| {
+            server.set_spill_threshold(Some(8));
+            server
+                .register_route(
+                    Route::new("/upload", Verb::Post),
+                    Box::new(|req| {
+                        let mut body = String::new();
+                        req.body_reader()
+                            .unwrap()
+                            .read_to_string(&mut body)
+                            .unwrap();
+
+                        let mut response = Response::new();
+                        let spilled = req.spilled_body_path().is_some();
+                        response.set_body(&format!("{} {} {}", spilled, req.body_len(), body));
+                        Ok(response)
+                    }),
+                )
+                .unwrap();
+        })
+        .await;
+
+        let reply = exchange(
+            addr,
+            b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        )
+        .await;
+
+        assert_eq!(reply.text(), "true 11 hello world");
+    }
+
+    #[tokio::test]
+    async fn oversized_head_is_refused_with_431() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/page", Verb::Get), reply("page"))
+                .unwrap();
+        })
+        .await;
+        let padding = "a".repeat(MAX_HEAD_SIZE);
+
+        // Whether or not the head ever ends, buffering stops at the cap.
+        for end in ["", "\r\n\r\n"] {
+            let request = format!(
+                "GET /page HTTP/1.1\r\nHost: x\r\nX-Padding: {}{}",
+                padding, end
+            );
+            let reply = exchange(addr, request.as_bytes()).await;
+
+            assert_eq!(reply.status, "HTTP/1.1 431 Request Header Fields Too Large");
+        }
+
+        // A head just under the cap is fine.
+        let request = format!(
+            "GET /page HTTP/1.1\r\nHost: x\r\nX-Padding: {}\r\n\r\n",
+            &padding[..MAX_HEAD_SIZE - 64]
+        );
+        assert_eq!(exchange(addr, request.as_bytes()).await.text(), "page");
+    }
+
+    #[tokio::test]
+    async fn connections_outside_the_access_list_are_closed_unanswered() {
+        let request = b"GET /page HTTP/1.1\r\nHost: x\r\n\r\n";
+        // A refused connection is closed without reading the request, so the
+        // close may arrive as a reset.
+        let refused = |addr| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let _ = stream.write_all(request).await;
+            let mut received = Vec::new();
+            let _ = stream.read_to_end(&mut received).await;
+            received.is_empty()
+        };
+        let page = |server: &mut Server| {
+            server
+                .register_route(Route::new("/page", Verb::Get), reply("page"))
+                .unwrap();
+        };
+
+        let addr = start(|server| {
+            page(server);
+            server.add_allowed_ips("10.0.0.0/8".parse().unwrap());
+        })
+        .await;
+        assert!(refused(addr).await);
+
+        let addr = start(|server| {
+            page(server);
+            server.add_allowed_ips("127.0.0.0/8".parse().unwrap());
+            server.add_denied_ips("127.0.0.1".parse().unwrap());
+        })
+        .await;
+        assert!(refused(addr).await);
+
+        let addr = start(|server| {
+            page(server);
+            server.add_allowed_ips("127.0.0.0/8".parse().unwrap());
+        })
+        .await;
+        assert_eq!(exchange(addr, request).await.text(), "page");
+    }
+
+    async fn failing_server(debug: bool) -> SocketAddr {
+        start(|server| {
+            server.set_debug(debug);
+            server
+                .register_route(
+                    Route::new("/fail", Verb::Get),
+                    Box::new(|_| {
+                        Err(HttpError::Internal(
+                            anyhow::anyhow!("disk on fire").context("problem saving"),
+                        ))
+                    }),
+                )
+                .unwrap();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn internal_error_detail_is_only_sent_in_debug_mode() {
+        let request = b"GET /fail HTTP/1.1\r\nHost: x\r\n\r\n";
+
+        let reply = exchange(failing_server(false).await, request).await;
+        assert_eq!(reply.status, "HTTP/1.1 500 Internal Server Error");
+        assert_eq!(reply.text(), "Internal Server Error");
+
+        let reply = exchange(failing_server(true).await, request).await;
+        assert_eq!(reply.status, "HTTP/1.1 500 Internal Server Error");
+        assert_eq!(
+            reply.text(),
+            "Internal Server Error\n\nGET /fail\nproblem saving: disk on fire"
+        );
+    }
+
+    #[tokio::test]
+    async fn line_breaks_before_the_request_line_are_skipped() {
+        let addr = start(|_| {}).await;
+
+        let reply = exchange(addr, b"\r\n\r\nGET /missing HTTP/1.1\r\nHost: x\r\n\r\n").await;
+
+        assert_eq!(reply.status, "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn large_bodies_reach_a_slow_reader_intact() {
+        let body: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = body.clone();
+        let addr = start(move |server| {
+            server
+                .register_route(
+                    Route::new("/big", Verb::Get),
+                    Box::new(move |_| {
+                        let mut response = Response::new();
+                        response.set_body_bytes(&body);
+                        Ok(response)
+                    }),
+                )
+                .unwrap();
+        })
+        .await;
+
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(8 * 1024).unwrap();
+        let mut stream = socket.connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /big HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        let mut buf = [0; 8 * 1024];
+        for reads in 1.. {
+            let bytes_read = stream.read(&mut buf).await.unwrap();
+            if bytes_read == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..bytes_read]);
+            if reads % 32 == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }
+
+        let reply = parse_reply(&received);
+        assert_eq!(reply.header("Content-Length"), Some("3145728"));
+        assert!(reply.body == expected, "body was corrupted");
+    }
+
+    // Registers `/things/:id` and then `/things/:name`, which only differ in
+    // their parameter names, under `policy`, returning whether the second
+    // registration was accepted and which handler ends up answering.
+    async fn register_duplicate(policy: DuplicateRoutes) -> (bool, String) {
+        let mut accepted = false;
+        let addr = start(|server| {
+            server.set_duplicate_routes(policy);
+            server
+                .register_route(Route::new("/things/:id", Verb::Get), reply("first"))
+                .unwrap();
+            accepted = server
+                .register_route(Route::new("/things/:name", Verb::Get), reply("second"))
+                .is_ok();
+        })
+        .await;
+
+        let reply = exchange(addr, b"GET /things/1 HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        (accepted, reply.text())
+    }
+
+    #[tokio::test]
+    async fn each_duplicate_route_policy() {
+        assert_eq!(
+            register_duplicate(DuplicateRoutes::Error).await,
+            (false, "first".to_string())
+        );
+        assert_eq!(
+            register_duplicate(DuplicateRoutes::Warn).await,
+            (true, "first".to_string())
+        );
+        assert_eq!(
+            register_duplicate(DuplicateRoutes::LastWins).await,
+            (true, "second".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_route_error_names_the_route() {
+        let mut server = Server::new("127.0.0.1:0").await.unwrap();
+        server.set_duplicate_routes(DuplicateRoutes::Error);
+        server
+            .register_route(Route::new("/a", Verb::Post), reply(""))
+            .unwrap();
+
+        // A different verb on the same path is not a duplicate.
+        assert!(server
+            .register_route(Route::new("/a", Verb::Get), reply(""))
+            .is_ok());
+        let err = server
+            .register_route(Route::new("/a", Verb::Post), reply(""))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "a route for POST /a is already registered");
+    }
+
+    #[tokio::test]
+    async fn connect_is_not_implemented_unless_routed() {
+        let request = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n";
+
+        let refused = exchange(start(|_| {}).await, request).await;
+        assert_eq!(refused.status, "HTTP/1.1 501 Not Implemented");
+        assert_eq!(refused.text(), "CONNECT tunnels are not supported");
+
+        let addr = start(|server| {
+            server
+                .register_route(
+                    Route::new("example.com:443", Verb::Connect),
+                    reply("tunnel"),
+                )
+                .unwrap();
+        })
+        .await;
+        let tunnelled = exchange(addr, request).await;
+        assert_eq!(tunnelled.status, "HTTP/1.1 200 OK");
+        assert_eq!(tunnelled.text(), "tunnel");
+    }
+
+    // Declares `declared` bytes of body, sends `sent` of them and then waits
+    // with the connection open.
+    async fn stall(addr: SocketAddr, declared: usize, sent: usize) -> Reply {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n",
+            declared
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(&vec![b'x'; sent]).await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        parse_reply(&response)
+    }
+
+    #[tokio::test]
+    async fn a_stalled_request_times_out_with_408() {
+        let addr = start(|server| {
+            server.set_read_timeout(Some(Duration::from_millis(100)));
+            server.set_spill_threshold(Some(1024));
+            server
+                .register_route(Route::new("/upload", Verb::Post), reply("got "))
+                .unwrap();
+        })
+        .await;
+
+        // Once in memory and once mid-way through spilling to a temp file.
+        for (declared, sent) in [(10, 5), (4096, 2048)] {
+            let reply = stall(addr, declared, sent).await;
+            assert_eq!(reply.status, "HTTP/1.1 408 Request Timeout", "{}", declared);
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_split_across_many_writes_are_reassembled() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/upload", Verb::Post), reply("got "))
+                .unwrap();
+            server
+                .register_route(Route::new("/page", Verb::Get), reply("page"))
+                .unwrap();
+        })
+        .await;
+
+        let pieces: [&[u8]; 6] = [
+            b"POST /upl",
+            b"oad HTTP/1.1\r\nHost: x\r\nContent-Len",
+            b"gth: 10\r\n\r",
+            b"\nhello",
+            b" you",
+            b"rGET /page HTTP/1.1\r\nHost: x\r\n\r\n",
+        ];
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for piece in pieces {
+            stream.write_all(piece).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        stream.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+
+        let replies = parse_replies(&received);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].text(), "got hello your");
+        assert_eq!(replies[1].text(), "page");
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_answered_in_order() {
+        let addr = start(|server| {
+            for path in ["/a", "/b", "/c"] {
+                server
+                    .register_route(Route::new(path, Verb::Get), reply(path))
+                    .unwrap();
+            }
+        })
+        .await;
+
+        let replies = exchange_all(
+            addr,
+            b"GET /c HTTP/1.1\r\nHost: x\r\n\r\n\
+              GET /a HTTP/1.1\r\nHost: x\r\n\r\n\
+              GET /b HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+
+        let bodies: Vec<String> = replies.iter().map(Reply::text).collect();
+        assert_eq!(bodies, ["/c", "/a", "/b"]);
+    }
+
+    #[tokio::test]
+    async fn connections_close_when_asked_or_for_http_1_0() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/page", Verb::Get), reply("page"))
+                .unwrap();
+        })
+        .await;
+
+        for (first, connection, answered) in [
+            ("GET /page HTTP/1.1\r\nHost: x\r\n\r\n", None, 2),
+            (
+                "GET /page HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+                Some("close"),
+                1,
+            ),
+            ("GET /page HTTP/1.0\r\n\r\n", Some("close"), 1),
+            (
+                "GET /page HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+                Some("keep-alive"),
+                2,
+            ),
+        ] {
+            let request = format!("{}GET /page HTTP/1.1\r\nHost: x\r\n\r\n", first);
+            let replies = exchange_all(addr, request.as_bytes()).await;
+
+            assert_eq!(replies.len(), answered, "{:?}", first);
+            assert_eq!(replies[0].header("Connection"), connection, "{:?}", first);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_refused_and_the_connection_closed() {
+        let addr = start(|server| {
+            server.set_max_body_size(16);
+            server
+                .register_route(Route::new("/upload", Verb::Post), reply(""))
+                .unwrap();
+            server
+                .register_route(Route::new("/page", Verb::Get), reply("page"))
+                .unwrap();
+        })
+        .await;
+
+        let replies = exchange_all(
+            addr,
+            b"POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 17\r\n\r\n\
+              01234567890123456\
+              GET /page HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, "HTTP/1.1 413 Content Too Large");
+        assert_eq!(replies[0].header("Connection"), Some("close"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_kept_alive_connections() {
+        let mut server = Server::new("127.0.0.1:0").await.unwrap();
+        server
+            .register_route(Route::new("/slow", Verb::Post), reply("done "))
+            .unwrap();
+        server
+            .register_route(Route::new("/page", Verb::Get), reply("page"))
+            .unwrap();
+        let addr = server.local_addr();
+        let (trigger, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let listening = tokio::spawn(Server::listen_until(Arc::new(server), async {
+            let _ = shutdown.await;
+        }));
+
+        // One connection idles between requests, the other is mid-request.
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        idle.write_all(b"GET /page HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        let mut busy = TcpStream::connect(addr).await.unwrap();
+        busy.write_all(b"GET /page HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        for stream in [&mut idle, &mut busy] {
+            let bytes_read = stream.read(&mut buf).await.unwrap();
+            assert_eq!(parse_reply(&buf[..bytes_read]).text(), "page");
+        }
+        busy.write_all(b"POST /slow HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\nab")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        trigger.send(()).unwrap();
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), idle.read_to_end(&mut received))
+            .await
+            .expect("the idle connection was left open")
+            .unwrap();
+        assert!(received.is_empty());
+
+        busy.write_all(b"cd").await.unwrap();
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), busy.read_to_end(&mut received))
+            .await
+            .expect("the busy connection was left open")
+            .unwrap();
+        let reply = parse_reply(&received);
+        assert_eq!(reply.text(), "done abcd");
+        assert_eq!(reply.header("Connection"), Some("close"));
+        listening.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_most_specific_overlapping_route_wins() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/users/*rest", Verb::Get), reply("rest"))
+                .unwrap();
+            server
+                .register_route(Route::new("/users/:id", Verb::Get), reply("id"))
+                .unwrap();
+            server
+                .register_route(Route::new("/users/me", Verb::Get), reply("me"))
+                .unwrap();
+            server
+                .register_route(Route::new("/:section/:id", Verb::Get), reply("section"))
+                .unwrap();
+        })
+        .await;
+
+        let replies = exchange_all(
+            addr,
+            b"GET /users/me HTTP/1.1\r\nHost: x\r\n\r\n\
+              GET /users/7 HTTP/1.1\r\nHost: x\r\n\r\n\
+              GET /users/7/posts HTTP/1.1\r\nHost: x\r\n\r\n\
+              GET /teams/7 HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+
+        let bodies: Vec<String> = replies.iter().map(Reply::text).collect();
+        assert_eq!(bodies, ["me", "id", "rest", "section"]);
+    }
+
+    #[tokio::test]
+    async fn other_verbs_on_a_routed_path_get_405_with_allow() {
+        let addr = start(|server| {
+            server
+                .register_route(Route::new("/users/:id", Verb::Get), reply(""))
+                .unwrap();
+            server
+                .register_route(Route::new("/users/:name", Verb::Delete), reply(""))
+                .unwrap();
+        })
+        .await;
+
+        let replies = exchange_all(
+            addr,
+            b"PUT /users/7 HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n\
+              PUT /teams/7 HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+
+        assert_eq!(replies[0].status, "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(
+            replies[0].header("Allow"),
+            Some("GET, DELETE, HEAD, OPTIONS")
+        );
+        assert_eq!(replies[1].status, "HTTP/1.1 404 Not Found");
+    }
+}
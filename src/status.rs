@@ -0,0 +1,197 @@
+use std::fmt::Display;
+
+/// An HTTP status code. The associated constants cover the codes this server
+/// sends itself, and [`reason_phrase`] knows the registered phrase for the
+/// rest of the common ones. Other codes can be made from a `u32`, which is
+/// taken as is: only three-digit codes (100-999) make a valid status line, so
+/// keeping to those is up to the caller.
+///
+/// [`reason_phrase`]: StatusCode::reason_phrase
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatusCode(u32);
+
+/// The first digit of a status code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusClass {
+    Informational,
+    Success,
+    Redirection,
+    ClientError,
+    ServerError,
+}
+
+impl StatusCode {
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+    pub const OK: StatusCode = StatusCode(200);
+    pub const CREATED: StatusCode = StatusCode(201);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const PARTIAL_CONTENT: StatusCode = StatusCode(206);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const UNAUTHORIZED: StatusCode = StatusCode(401);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const REQUEST_TIMEOUT: StatusCode = StatusCode(408);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const RANGE_NOT_SATISFIABLE: StatusCode = StatusCode(416);
+    pub const UPGRADE_REQUIRED: StatusCode = StatusCode(426);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const INSUFFICIENT_STORAGE: StatusCode = StatusCode(507);
+
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+
+    /// The class of the code, or `None` for codes outside 100-599.
+    pub fn class(self) -> Option<StatusClass> {
+        match self.0 {
+            100..=199 => Some(StatusClass::Informational),
+            200..=299 => Some(StatusClass::Success),
+            300..=399 => Some(StatusClass::Redirection),
+            400..=499 => Some(StatusClass::ClientError),
+            500..=599 => Some(StatusClass::ServerError),
+            _ => None,
+        }
+    }
+
+    /// 4xx and 5xx.
+    pub fn is_error(self) -> bool {
+        matches!(
+            self.class(),
+            Some(StatusClass::ClientError | StatusClass::ServerError)
+        )
+    }
+
+    /// Statuses that by definition never carry a message body.
+    pub fn permits_body(self) -> bool {
+        !matches!(self.0, 100..=199 | 204 | 304)
+    }
+
+    /// The reason phrase registered for the code (RFC 9110 wording), or an
+    /// empty string for codes it does not know.
+    pub fn reason_phrase(self) -> &'static str {
+        match self.0 {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            203 => "Non-Authoritative Information",
+            204 => "No Content",
+            205 => "Reset Content",
+            206 => "Partial Content",
+            300 => "Multiple Choices",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            402 => "Payment Required",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            406 => "Not Acceptable",
+            407 => "Proxy Authentication Required",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            410 => "Gone",
+            411 => "Length Required",
+            412 => "Precondition Failed",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            417 => "Expectation Failed",
+            421 => "Misdirected Request",
+            422 => "Unprocessable Content",
+            426 => "Upgrade Required",
+            428 => "Precondition Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            507 => "Insufficient Storage",
+            _ => "",
+        }
+    }
+}
+
+impl From<u32> for StatusCode {
+    fn from(code: u32) -> Self {
+        StatusCode(code)
+    }
+}
+
+impl From<StatusCode> for u32 {
+    fn from(status: StatusCode) -> Self {
+        status.0
+    }
+}
+
+impl Display for StatusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn class_follows_the_first_digit() {
+        let cases = [
+            (101, Some(StatusClass::Informational)),
+            (204, Some(StatusClass::Success)),
+            (304, Some(StatusClass::Redirection)),
+            (404, Some(StatusClass::ClientError)),
+            (599, Some(StatusClass::ServerError)),
+            (99, None),
+            (600, None),
+        ];
+
+        for (code, class) in cases {
+            assert_eq!(StatusCode::from(code).class(), class, "{}", code);
+        }
+        assert!(StatusCode::NOT_FOUND.is_error());
+        assert!(StatusCode::SERVICE_UNAVAILABLE.is_error());
+        assert!(!StatusCode::NOT_MODIFIED.is_error());
+    }
+
+    #[test]
+    fn only_1xx_204_and_304_forbid_a_body() {
+        for code in [100, 101, 204, 304] {
+            assert!(!StatusCode::from(code).permits_body(), "{}", code);
+        }
+        for code in [200, 201, 206, 301, 400, 500] {
+            assert!(StatusCode::from(code).permits_body(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn reason_phrases_are_the_registered_ones() {
+        assert_eq!(StatusCode::OK.reason_phrase(), "OK");
+        assert_eq!(
+            StatusCode::PAYLOAD_TOO_LARGE.reason_phrase(),
+            "Content Too Large"
+        );
+        assert_eq!(
+            StatusCode::REQUEST_TIMEOUT.reason_phrase(),
+            "Request Timeout"
+        );
+        assert_eq!(StatusCode::from(299).reason_phrase(), "");
+        assert_eq!(StatusCode::from(418).to_string(), "418");
+    }
+}
@@ -0,0 +1,114 @@
+use std::future::Future;
+use std::pin::Pin;
+use tokio::net::TcpStream;
+
+use crate::base64;
+use crate::request::has_token;
+use crate::{Request, Response, StatusCode, Verb};
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const SUPPORTED_VERSION: &str = "13";
+
+pub type WebSocketHandler =
+    Box<dyn Fn(TcpStream) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+pub(crate) fn is_upgrade_request(req: &Request) -> bool {
+    has_token(req.get_header("Upgrade"), "websocket")
+}
+
+/// Validates the client handshake, returning the `101 Switching Protocols`
+/// response on success or the error response to send instead.
+pub(crate) fn handshake(req: &Request) -> Result<Response, Response> {
+    if req.verb != Verb::Get || !has_token(req.get_header("Connection"), "upgrade") {
+        return Err(Response::new_400());
+    }
+
+    if req.get_header("Sec-WebSocket-Version").map(str::trim) != Some(SUPPORTED_VERSION) {
+        let mut response = Response::with_status(StatusCode::UPGRADE_REQUIRED);
+        response.set_header("Sec-WebSocket-Version", SUPPORTED_VERSION);
+        return Err(response);
+    }
+
+    let key = match req.get_header("Sec-WebSocket-Key") {
+        Some(key) if !key.trim().is_empty() => key.trim(),
+        _ => return Err(Response::new_400()),
+    };
+
+    let mut response = Response::with_status(StatusCode::SWITCHING_PROTOCOLS);
+    response.set_header("Upgrade", "websocket");
+    response.set_header("Connection", "Upgrade");
+    response.set_header("Sec-WebSocket-Accept", &accept_key(key));
+    Ok(response)
+}
+
+fn accept_key(key: &str) -> String {
+    base64::encode(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+fn sha1(message: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((message.len() as u64) * 8).to_be_bytes());
+
+    for block in padded.chunks(64) {
+        let mut words = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            words[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (chunk, value) in digest.chunks_mut(4).zip(state) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    // The example handshake from RFC 6455 section 1.3.
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}